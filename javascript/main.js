@@ -30,10 +30,10 @@ let lastTime = 0;
 let lastMemory = null;
 
 function wrap(names) {
-    for (name of names) {
+    for (const name of names) {
         global[name] = (...args) => {
             if (wasm_module) {
-                wasm_module[name](...args);
+                return wasm_module[name](...args);
             }
         }
     }
@@ -43,6 +43,11 @@ wrap([
     'testDistTransform',
     'testMinCut',
     'testRoomCut',
+    'memoryGet',
+    'memorySet',
+    'memoryDelete',
+    'cacheStats',
+    'creepOutcomes',
 ]);
 
 module.exports.loop = function () {
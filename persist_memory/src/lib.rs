@@ -1,5 +1,6 @@
 pub use persist_memory_derive::*;
-use minicbor::{Encode, Decode};
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hash};
 // TODO: add a way for persisted to be known to have encode and decode from minicbor
 
 /// A trait for types where only parts of it need to be fully serialized
@@ -13,7 +14,7 @@ pub trait Persist {
 
   /// Revive the important information from the persisted memory.
   ///
-  /// For certain
+  /// Anything that wasn't persisted starts out as its default.
   fn revive(stored: Self::Persisted) -> Self;
 }
 
@@ -52,6 +53,60 @@ macro_rules! base_impl {
   };
 }
 
+base_impl!(bool);
+base_impl!(u8);
+base_impl!(u16);
 base_impl!(u32);
-use screeps::local::ObjectId;
+base_impl!(u64);
+base_impl!(i32);
+base_impl!(String);
+use screeps::local::{ObjectId, LocalCostMatrix};
+use screeps::{Position, RoomName, RoomXY};
 base_impl!(<T> => ObjectId<T>);
+base_impl!(Position);
+base_impl!(RoomName);
+base_impl!(RoomXY);
+base_impl!(LocalCostMatrix);
+
+impl<T: Persist> Persist for Option<T> {
+  type Persisted = Option<T::Persisted>;
+  fn to_persist(&self) -> Self::Persisted {
+    self.as_ref().map(T::to_persist)
+  }
+  fn revive(stored: Self::Persisted) -> Self {
+    stored.map(T::revive)
+  }
+}
+
+impl<T: Persist> Persist for Vec<T> {
+  type Persisted = Vec<T::Persisted>;
+  fn to_persist(&self) -> Self::Persisted {
+    self.iter().map(T::to_persist).collect()
+  }
+  fn revive(stored: Self::Persisted) -> Self {
+    stored.into_iter().map(T::revive).collect()
+  }
+}
+
+// Keys are always stored as they are; only the values get trimmed.
+
+impl<K: Ord + Clone, V: Persist> Persist for BTreeMap<K, V> {
+  type Persisted = BTreeMap<K, V::Persisted>;
+  fn to_persist(&self) -> Self::Persisted {
+    self.iter().map(|(k, v)| (k.clone(), v.to_persist())).collect()
+  }
+  fn revive(stored: Self::Persisted) -> Self {
+    stored.into_iter().map(|(k, v)| (k, V::revive(v))).collect()
+  }
+}
+
+impl<K, V, S> Persist for HashMap<K, V, S>
+where K: Eq + Hash + Clone, V: Persist, S: BuildHasher + Default {
+  type Persisted = HashMap<K, V::Persisted, S>;
+  fn to_persist(&self) -> Self::Persisted {
+    self.iter().map(|(k, v)| (k.clone(), v.to_persist())).collect()
+  }
+  fn revive(stored: Self::Persisted) -> Self {
+    stored.into_iter().map(|(k, v)| (k, V::revive(v))).collect()
+  }
+}
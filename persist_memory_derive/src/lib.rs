@@ -1,13 +1,8 @@
-// TODO: call to_persist/revive on fields and make macro for default
-// implementation (which is just clone for to_persist and move for
-// revive).
-// TODO: add Default implementation for persisted. Possibly just using
-// the trait system?
 extern crate proc_macro;
 use quote::quote;
 use syn;
 use syn::punctuated::Punctuated;
-use syn::{Token, DataStruct, DataEnum, Fields, Field, Attribute, Variant};
+use syn::{Token, DataStruct, DataEnum, Fields, Field, Attribute, Variant, Visibility};
 use syn::parse::{Parse, ParseBuffer};
 use proc_macro2::{Ident, Span, TokenStream};
 
@@ -24,28 +19,48 @@ fn parse_index(stream: &ParseBuffer<'_>) -> syn::Result<u32> {
     .map_err(|_| syn::Error::new(n.span(), "expected `u32` value"))
 }
 
-fn get_attr_data(attrs: &[Attribute]) -> syn::Result<Option<PersistAttr>> {
-  let mut out: Option<PersistAttr> = None;
+/// The two forms a `#[persist(...)]` attribute can take.
+enum PersistArg {
+  /// `#[persist(n)]` or `#[persist(n, "path::to::mod")]`.
+  Persist(PersistAttr),
+  /// `#[persist(rebuild = "path::to::fn")]` for a field that isn't persisted
+  /// but is recomputed by calling the function with the revived value.
+  Rebuild(syn::Path),
+}
+
+fn parse_persist_arg(stream: &ParseBuffer<'_>) -> syn::Result<PersistArg> {
+  if stream.peek(syn::Ident) {
+    let key = Ident::parse(stream)?;
+    if key != "rebuild" {
+      return Err(syn::Error::new(key.span(), "expected an index or `rebuild`"));
+    }
+    <Token![=]>::parse(stream)?;
+    let path = stream.parse::<syn::LitStr>()?;
+    return Ok(PersistArg::Rebuild(path.parse()?))
+  }
+  let index = parse_index(stream)?;
+  let coder_path = if stream.peek(Token![,]) {
+    <Token![,]>::parse(stream)?;
+    Some(stream.parse::<syn::LitStr>()?)
+  } else {
+    None
+  };
+  Ok(PersistArg::Persist(PersistAttr {
+    index, coder_path
+  }))
+}
+
+fn get_attr_args(attrs: &[Attribute]) -> syn::Result<Option<PersistArg>> {
+  let mut out: Option<PersistArg> = None;
 
   for attr in attrs.iter() {
     if attr.meta.path().is_ident("persist") {
-      let pattr = attr.parse_args_with(|stream: &'_ ParseBuffer<'_>| -> syn::Result<PersistAttr> {
-        let index = parse_index(stream)?;
-        let coder_path = if stream.peek(Token![,]) {
-          <Token![,]>::parse(stream)?;
-          Some(stream.parse::<syn::LitStr>()?)
-        } else {
-          None
-        };
-        Ok(PersistAttr {
-          index, coder_path
-        })
-      })?;
+      let arg = attr.parse_args_with(parse_persist_arg)?;
       // throw an error if we have two
       if out.is_some() {
         return Err(syn::Error::new_spanned(attr, "two #[persist(...)] attributes"));
       } else {
-        out = Some(pattr);
+        out = Some(arg);
       }
     }
   }
@@ -53,6 +68,52 @@ fn get_attr_data(attrs: &[Attribute]) -> syn::Result<Option<PersistAttr>> {
   Ok(out)
 }
 
+fn get_attr_data(attrs: &[Attribute]) -> syn::Result<Option<PersistAttr>> {
+  Ok(match get_attr_args(attrs)? {
+    Some(PersistArg::Persist(attr)) => Some(attr),
+    _ => None,
+  })
+}
+
+/// The function used to rebuild a field that isn't persisted, if any.
+fn get_rebuild(attrs: &[Attribute]) -> syn::Result<Option<syn::Path>> {
+  Ok(match get_attr_args(attrs)? {
+    Some(PersistArg::Rebuild(path)) => Some(path),
+    _ => None,
+  })
+}
+
+/// Statements that call the rebuild functions for the fields of `revived`,
+/// which is matched against `pattern_path` (the struct or variant) to reach them.
+///
+/// They run in declaration order after all of the persisted fields have been
+/// revived, so a rebuild function can use any persisted field and any
+/// field rebuilt before it.
+fn rebuild_fields(
+  fields: &Fields,
+  pattern_path: TokenStream,
+) -> syn::Result<TokenStream> {
+  fields.iter()
+    .enumerate()
+    .map(|(i, field)| -> syn::Result<TokenStream> {
+      let Some(path) = get_rebuild(&field.attrs)? else {
+        return Ok(TokenStream::new())
+      };
+      let member = match &field.ident {
+        Some(ident) => syn::Member::Named(ident.clone()),
+        None => syn::Member::Unnamed(syn::Index::from(i)),
+      };
+      Ok(quote! {
+        let value = #path(&revived);
+        #[allow(irrefutable_let_patterns)]
+        if let #pattern_path { #member: slot, .. } = &mut revived {
+          *slot = value;
+        }
+      })
+    })
+    .collect()
+}
+
 /// Utility for creating formatting based on fields.
 ///
 /// op takes the field, the identifier that should be used as a value
@@ -91,8 +152,11 @@ fn use_fields(
   })
 }
 
+/// Declare the persisted fields. Each one is stored as the `Persisted` type
+/// of the original field, so nested `Persist` types are trimmed as well.
 fn make_persisted_fields(
   fields: &Fields,
+  field_vis: TokenStream,
 ) -> syn::Result<TokenStream> {
   use_fields(fields, |field, _var, opt_attr| opt_attr.map(|persist_attr| {
     let ty = &field.ty;
@@ -105,48 +169,80 @@ fn make_persisted_fields(
     };
     match &field.ident {
       Some(ident) => quote! {
-        #attr #ident : #ty,
+        #attr #field_vis #ident : <#ty as ::persist_memory::Persist>::Persisted,
       },
       None => quote! {
-        #attr #ty,
+        #attr #field_vis <#ty as ::persist_memory::Persist>::Persisted,
       },
     }
   }).unwrap_or_default())
 }
 
+/// How `use_field_vars` should use each field.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FieldUse {
+  /// Bind every persisted field in a pattern, ignoring the others.
+  BindAll,
+  /// Bind the fields of the persisted type in a pattern.
+  BindPersisted,
+  /// Build the persisted type out of the bound fields.
+  ToPersist,
+  /// Build the original type out of the bound persisted fields, using
+  /// `Default::default()` for anything that wasn't persisted.
+  Revive,
+}
+
+/// Utility function for dealing with generating things that pattern match
+/// on fields or construct fields.
+fn use_field_vars(
+  fields: &Fields,
+  usage: FieldUse,
+) -> syn::Result<TokenStream> {
+  use_fields(fields, |field, var, opt_attr| {
+    let prefix = field.ident.as_ref()
+      .map_or(TokenStream::new(), |field_name| quote! {
+        #field_name :
+      });
+    let ty = &field.ty;
+    let value = match (usage, opt_attr.is_some()) {
+      // the binding is the field name, so use the shorthand pattern.
+      (FieldUse::BindAll, true) | (FieldUse::BindPersisted, true) =>
+        return quote! { #var, },
+      (FieldUse::BindAll, false) => quote! { _ },
+      (FieldUse::BindPersisted, false) | (FieldUse::ToPersist, false) =>
+        return TokenStream::new(),
+      (FieldUse::ToPersist, true) => quote! {
+        ::persist_memory::Persist::to_persist(#var)
+      },
+      (FieldUse::Revive, true) => quote! {
+        <#ty as ::persist_memory::Persist>::revive(#var)
+      },
+      (FieldUse::Revive, false) => quote! { Default::default() },
+    };
+    quote! {
+      #prefix #value,
+    }
+  })
+}
+
 fn for_struct(
   name: Ident,
+  vis: Visibility,
   persisted_ident: Ident,
   inp: DataStruct
 ) -> syn::Result<TokenStream> {
   // filter out fields that I'll be persisting.
-  let field_decl = make_persisted_fields(&inp.fields)?;
-  let to_persist_conv = quote! { .to_persist() };
-  let revive_conv = quote! { .revive() };
-  let to_persist_pattern = use_field_vars(&inp.fields, false, true, None)?;
-  let to_persist_fields = use_field_vars(&inp.fields, true, false, Some(&to_persist_conv))?;
-  let revive_pattern = use_field_vars(&inp.fields, true, true, None)?;
-  let revive_fields = use_field_vars(&inp.fields, false, false, Some(&revive_conv))?;
-  /*
-  let to_persist_fields = use_fields(&inp.fields, |field, opt_attr| {
-    let name = &field.ident;
-    opt_attr.map(|attr| quote! {
-      #name: self.#name,
-    }).unwrap_or_default()
-  })?;
-  let revive_fields = use_fields(&inp.fields, |field, opt_attr| {
-    let name = &field.ident;
-    opt_attr.map_or(quote!{
-      #name: Default::default(),
-    }, |attr| quote! {
-      #name: stored.#name,
-    })
-  })?;
-  */
+  let field_decl = make_persisted_fields(&inp.fields, quote! { pub })?;
+  let to_persist_pattern = use_field_vars(&inp.fields, FieldUse::BindAll)?;
+  let to_persist_fields = use_field_vars(&inp.fields, FieldUse::ToPersist)?;
+  let revive_pattern = use_field_vars(&inp.fields, FieldUse::BindPersisted)?;
+  let revive_fields = use_field_vars(&inp.fields, FieldUse::Revive)?;
+  let rebuild = rebuild_fields(&inp.fields, quote! { #name })?;
   let semi = &inp.semi_token;
   let mod_name = Ident::new(&format!("persist_impl_{}", name), Span::call_site());
 
-  let output = quote! {
+  Ok(quote! {
+    #[allow(non_snake_case)]
     mod #mod_name {
       use minicbor;
       use super::*;
@@ -154,12 +250,6 @@ fn for_struct(
       #[derive(::minicbor::Encode, ::minicbor::Decode)]
       pub struct #persisted_ident #field_decl #semi
 
-      impl Default for #persisted_ident {
-        fn default() -> #persisted_ident {
-          #name::default().to_persist()
-        }
-      }
-
       impl ::persist_memory::Persist for #name {
         type Persisted = #persisted_ident;
         fn to_persist(&self) -> Self::Persisted {
@@ -169,15 +259,18 @@ fn for_struct(
         }
 
         fn revive(stored: Self::Persisted) -> Self {
-          match stored {
+          #[allow(unused_mut)]
+          let mut revived = match stored {
             #persisted_ident #revive_pattern => #name #revive_fields
-          }
+          };
+          #rebuild
+          revived
         }
       }
     }
-  };
-  println!("pretty {}", output);
-  Ok(output)
+    #[allow(unused_imports)]
+    #vis use #mod_name::#persisted_ident;
+  })
 }
 
 fn use_variants<'a>(
@@ -193,75 +286,72 @@ fn use_variants<'a>(
     .collect::<syn::Result<TokenStream>>()
 }
 
-/// Utility function for dealing with generating things that pattern match
-/// on fields or construct fields.
-///
-/// If omit_unpersisted is false, we use `Default::default()` as a replacement
-/// for `var`, the default `Ident` provided by `use_fields`.
-///
-/// `for_pattern` indicates whether this is for a pattern or not, and whether
-/// `Default::default()` should be used or the default `var` ident from `use_fields`.
-fn use_field_vars(
-  fields: &Fields,
-  omit_unpersisted: bool,
-  for_pattern: bool,
-  conv: Option<&TokenStream>,
-) -> syn::Result<TokenStream> {
-  use_fields(fields, |field, var, opt_attr| {
-    if omit_unpersisted && opt_attr.is_none() {
-      TokenStream::new()
-    } else {
-      let prefix = field.ident.as_ref()
-        .map_or(TokenStream::new(), |field_name| quote! {
-          #field_name :
-        });
-      let value = if for_pattern {
-        quote! { #var }
-      } else if let Some(attr) = opt_attr {
-        quote! { #var #conv }
-      } else {
-        quote! { Default::default() }
-      };
-      quote! {
-        #prefix #value,
-      }
-    }
-  })
-}
+/// The index of the variant that stands in for the ones that aren't
+/// persisted. It revives as the enum's `Default`, so it doesn't matter which
+/// variant that is.
+const UNPERSISTED_INDEX: u32 = u32::MAX;
 
 fn for_enum(
   name: Ident,
+  vis: Visibility,
   persisted_ident: Ident,
   inp: DataEnum,
 ) -> syn::Result<TokenStream> {
-  let enum_decl = use_variants(inp.variants.iter(), |variant, opt_attr| {
+  let mut has_unpersisted = false;
+  for variant in inp.variants.iter() {
+    match get_attr_data(&variant.attrs)? {
+      Some(attr) if attr.index == UNPERSISTED_INDEX => {
+        return Err(syn::Error::new_spanned(variant,
+          "this index is used for the variants that aren't persisted"));
+      }
+      Some(_) => (),
+      None => has_unpersisted = true,
+    }
+  }
+  let mut enum_decl = use_variants(inp.variants.iter(), |variant, opt_attr| {
     let variant_name = &variant.ident;
-    let fields = make_persisted_fields(&variant.fields)?;
-    Ok(opt_attr.map(|attr| {
+    if get_rebuild(&variant.attrs)?.is_some() {
+      return Err(syn::Error::new_spanned(variant,
+        "only fields can be rebuilt; mark the variant with an index instead"));
+    }
+    let fields = make_persisted_fields(&variant.fields, TokenStream::new())?;
+    opt_attr.map_or(Ok(TokenStream::new()), |attr| {
+      if let Some(path) = attr.coder_path {
+        return Err(syn::Error::new(path.span(),
+          "a variant can't have a coder path; put it on the field instead"));
+      }
       let n = proc_macro2::Literal::u32_unsuffixed(attr.index);
-      quote! {
+      Ok(quote! {
         #[n(#n)] #variant_name #fields,
-      }
-    }).unwrap_or_default())
+      })
+    })
   })?;
+  let mut revive_unpersisted = TokenStream::new();
+  if has_unpersisted {
+    let n = proc_macro2::Literal::u32_unsuffixed(UNPERSISTED_INDEX);
+    enum_decl.extend(quote! { #[n(#n)] __Unpersisted, });
+    revive_unpersisted = quote! {
+      #persisted_ident::__Unpersisted => #name::default(),
+    };
+  }
   // fn to_persist(&self) -> Self::Persisted {
   //   match self {
   //     A => A,
-  //     B(a, b, c,) => B(a, c,)
+  //     B(a, _, c,) => B(a.to_persist(), c.to_persist(),)
+  //     C => __Unpersisted,
   //   }
   // }
   let to_persist_body = use_variants(inp.variants.iter(), |variant, opt_attr| {
-    let conv = quote! { .to_persist() };
     let variant_name = &variant.ident;
-    let pattern = use_field_vars(&variant.fields, false, true, None)?;
+    let pattern = use_field_vars(&variant.fields, FieldUse::BindAll)?;
     Ok(if opt_attr.is_some() {
-      let fields = use_field_vars(&variant.fields, true, false, Some(&conv))?;
+      let fields = use_field_vars(&variant.fields, FieldUse::ToPersist)?;
       quote! {
         #name::#variant_name #pattern => #persisted_ident::#variant_name #fields,
       }
     } else {
       quote! {
-        #name::#variant_name #pattern => #name::default().to_persist(),
+        #name::#variant_name #pattern => #persisted_ident::__Unpersisted,
       }
     })
   })?;
@@ -271,36 +361,35 @@ fn for_enum(
   // fn revive(stored: Self::Persisted) -> Self {
   //   match stored {
   //     A => A
-  //     B(a, c,) => B(a, Default::default(), c,)
+  //     B(a, c,) => B(revive(a), Default::default(), revive(c),)
+  //     __Unpersisted => Self::default(),
   //   }
   // }
   let revive_body = use_variants(inp.variants.iter(), |variant, opt_attr| {
-    opt_attr.map_or(Ok(TokenStream::new()), |attr| {
-      let conv = quote! { .revive() };
-      let pattern = use_field_vars(&variant.fields, true, true, None)?;
+    opt_attr.map_or(Ok(TokenStream::new()), |_attr| {
+      let pattern = use_field_vars(&variant.fields, FieldUse::BindPersisted)?;
       let variant_name = &variant.ident;
-      let fields = use_field_vars(&variant.fields, false, false, Some(&conv))?;
+      let fields = use_field_vars(&variant.fields, FieldUse::Revive)?;
+      let rebuild = rebuild_fields(&variant.fields, quote! { #name::#variant_name })?;
       Ok(quote! {
-        #persisted_ident :: #variant_name #pattern => #name::#variant_name #fields,
+        #persisted_ident :: #variant_name #pattern => {
+          #[allow(unused_mut)]
+          let mut revived = #name::#variant_name #fields;
+          #rebuild
+          revived
+        }
       })
     })
   })?;
   let mod_name = Ident::new(&format!("persist_impl_{}", name), Span::call_site());
-  // TODO: for to_persist I might need to add an option to have it add `.clone()`
-  let output = quote! {
+  Ok(quote! {
+    #[allow(non_snake_case)]
     mod #mod_name {
       use minicbor;
-      use persist_memory;
       use super::*;
       #[derive(::minicbor::Encode, ::minicbor::Decode)]
       pub enum #persisted_ident { #enum_decl }
 
-      impl Default for #persisted_ident {
-        fn default() -> #persisted_ident {
-          #name::default().to_persist()
-        }
-      }
-
       impl ::persist_memory::Persist for #name {
         type Persisted = #persisted_ident;
         fn to_persist(&self) -> Self::Persisted {
@@ -312,15 +401,27 @@ fn for_enum(
         fn revive(stored: Self::Persisted) -> Self {
           match stored {
             #revive_body
+            #revive_unpersisted
           }
         }
       }
     }
-  };
-  println!("pretty {}", output);
-  Ok(output)
+    #[allow(unused_imports)]
+    #vis use #mod_name::#persisted_ident;
+  })
 }
 
+/// Derive `Persist`, generating a `Persist{Name}` type that only holds the
+/// fields and variants marked with `#[persist(n)]` or `#[persist(n, "coder")]`.
+///
+/// Fields that aren't persisted are filled in with `Default::default()` on
+/// revive, unless they're marked `#[persist(rebuild = "path::to::fn")]`, in
+/// which case they're set to `fn(&revived)` once the rest of the value has
+/// been revived. That's how we survey the game state after a global reset.
+///
+/// Enums with variants that aren't persisted need to implement `Default`.
+/// Those variants are all written as one placeholder variant, which is
+/// revived as the default.
 #[proc_macro_derive(Persist, attributes(persist))]
 pub fn derive_persist_memory(
   tokens: proc_macro::TokenStream
@@ -328,14 +429,15 @@ pub fn derive_persist_memory(
   let input = syn::parse_macro_input!(tokens as syn::DeriveInput);
 
   let name = input.ident;
+  let vis = input.vis;
   // name of the new persisted identifier
   let persisted_ident = Ident::new(&format!("Persist{}", name),
     Span::call_site());
   // for now we won't worry about generics. We'll hand write instances for
   // those, especially given how annoying they are.
   let result = match input.data {
-    syn::Data::Struct(data) => for_struct(name, persisted_ident, data),
-    syn::Data::Enum(data) => for_enum(name, persisted_ident, data),
+    syn::Data::Struct(data) => for_struct(name, vis, persisted_ident, data),
+    syn::Data::Enum(data) => for_enum(name, vis, persisted_ident, data),
     syn::Data::Union(u) => {
       let msg = "deriving `PersistMemory` for a `union` is not supported";
       Err(syn::Error::new(u.union_token.span, msg))
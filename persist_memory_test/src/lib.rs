@@ -2,7 +2,7 @@
 mod tests {
   use persist_memory::*;
 
-  #[derive(Persist, Default)]
+  #[derive(Persist, Default, PartialEq, Debug)]
   struct Test1 {
     #[persist(0)] a: u32,
     b: u32,
@@ -12,7 +12,7 @@ mod tests {
   #[derive(Persist, Default)]
   struct Test2(#[persist(0)] u32, u32, #[persist(1)] u32);
 
-  #[derive(Persist)]
+  #[derive(Persist, PartialEq, Debug)]
   enum Test3 {
     VA,
     #[persist(0)] VB {
@@ -28,4 +28,70 @@ mod tests {
       Test3::VA
     }
   }
+
+  #[derive(Persist, PartialEq, Debug)]
+  struct Test4 {
+    #[persist(0)] inner: Vec<Test3>,
+    #[persist(1)] maybe: Option<Test1>,
+  }
+
+  fn double_a(t: &Test5) -> u32 {
+    t.a * 2
+  }
+
+  fn sum_fields(t: &Test6) -> u32 {
+    match t {
+      Test6::VA(a, b, _) => a + b,
+    }
+  }
+
+  #[derive(Persist, PartialEq, Debug)]
+  struct Test5 {
+    #[persist(0)] a: u32,
+    #[persist(rebuild = "double_a")] b: u32,
+  }
+
+  #[derive(Persist, PartialEq, Debug)]
+  enum Test6 {
+    #[persist(0)] VA(#[persist(0)] u32, #[persist(rebuild = "double_first")] u32,
+                     #[persist(rebuild = "sum_fields")] u32),
+  }
+
+  fn double_first(t: &Test6) -> u32 {
+    match t {
+      Test6::VA(a, _, _) => a * 2,
+    }
+  }
+
+  fn round_trip<T: Persist>(value: &T) -> T
+  where T::Persisted: minicbor::Encode<()> + for<'b> minicbor::Decode<'b, ()> {
+    let buffer = minicbor::to_vec(value.to_persist()).expect("encode");
+    T::revive(minicbor::decode(&buffer).expect("decode"))
+  }
+
+  #[test]
+  fn drops_unpersisted() {
+    let t1 = Test1 { a: 1, b: 2, c: 3 };
+    assert_eq!(round_trip(&t1), Test1 { a: 1, b: 0, c: 3 });
+    let t2 = round_trip(&Test2(1, 2, 3));
+    assert_eq!((t2.0, t2.1, t2.2), (1, 0, 3));
+  }
+
+  #[test]
+  fn unpersisted_variants_use_default() {
+    let t4 = Test4 {
+      inner: vec![Test3::VB { a: 1, b: 2, c: 3 }, Test3::VC(4, 5, 6), Test3::VA],
+      maybe: Some(Test1 { a: 1, b: 2, c: 3 }),
+    };
+    assert_eq!(round_trip(&t4), Test4 {
+      inner: vec![Test3::VB { a: 1, b: 0, c: 3 }, Test3::VC(4, 0, 6), Test3::VA],
+      maybe: Some(Test1 { a: 1, b: 0, c: 3 }),
+    });
+  }
+
+  #[test]
+  fn rebuilds_fields_in_order() {
+    assert_eq!(round_trip(&Test5 { a: 3, b: 0 }), Test5 { a: 3, b: 6 });
+    assert_eq!(round_trip(&Test6::VA(3, 0, 0)), Test6::VA(3, 6, 9));
+  }
 }
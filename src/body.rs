@@ -54,6 +54,11 @@ macro_rules! gen_body_design {
         self.base_cost() * self.scale_factor(max_energy)
       }
 
+      /// Whether a spawn with `budget` energy to spend can ever build this.
+      pub fn fits(&self, budget: u32) -> bool {
+        self.size() as u32 <= MAX_CREEP_SIZE && self.max_cost(budget) <= budget
+      }
+
       pub fn scale(&self, max_energy: u32) -> Vec<Part> {
         let scale = self.scale_factor(max_energy);
         self.scale_to_iter(scale).collect()
@@ -63,6 +68,8 @@ macro_rules! gen_body_design {
 }
 
 gen_body_design! {
+  // TOUGH goes first so it takes the damage before anything else.
+  tough_count => tough Tough
   work_count => work Work
   move_count => r#move Move
   carry_count => carry Carry
@@ -70,5 +77,4 @@ gen_body_design! {
   ranged_attack_count => ranged_attack RangedAttack
   heal_count => heal Heal
   claim_count => claim Claim
-  tough_count => tough Tough
 }
@@ -0,0 +1,197 @@
+//! Console commands for looking at and editing the Rust `Memory`.
+//!
+//! Paths are dot separated keys into the JSON form of `Memory`, like
+//! `creeps.Worker-12` or `spawns`. An empty path is the whole memory.
+//!
+//! ```js
+//! memoryGet("creeps.Worker-12")
+//! memorySet("creeps.Worker-12", '{"Worker": "Idle"}')
+//! memoryDelete("creeps.Worker-12")
+//! memorySet("settings.save_interval", "20")
+//! cacheStats()
+//! creepOutcomes()
+//! ```
+
+use js_sys::JsString;
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::creeps::outcomes;
+use crate::memory::Memory;
+use crate::storage::cache;
+use crate::storage::serialization::{peek_memory, replace_memory};
+
+const NOT_LOADED: &str = "memory has not been loaded yet";
+
+fn path_keys(path: &str) -> Vec<&str> {
+  path.split('.').filter(|key| !key.is_empty()).collect()
+}
+
+/// Find the value at the path.
+fn get_path<'a>(root: &'a Value, keys: &[&str]) -> Option<&'a Value> {
+  keys.iter().try_fold(root, |value, key| match value {
+    Value::Object(map) => map.get(*key),
+    Value::Array(vec) => key.parse::<usize>().ok().and_then(|i| vec.get(i)),
+    _ => None,
+  })
+}
+
+fn get_path_mut<'a>(root: &'a mut Value, keys: &[&str]) -> Option<&'a mut Value> {
+  keys.iter().try_fold(root, |value, key| match value {
+    Value::Object(map) => map.get_mut(*key),
+    Value::Array(vec) => key.parse::<usize>().ok().and_then(|i| vec.get_mut(i)),
+    _ => None,
+  })
+}
+
+/// Set the value at the path, adding the last key if it's a new map entry.
+fn set_path(root: &mut Value, keys: &[&str], new: Value) -> Result<(), String> {
+  let Some((last, parent_keys)) = keys.split_last() else {
+    *root = new;
+    return Ok(())
+  };
+  match get_path_mut(root, parent_keys) {
+    Some(Value::Object(map)) => {
+      map.insert(last.to_string(), new);
+      Ok(())
+    }
+    Some(Value::Array(vec)) => {
+      let slot = last.parse::<usize>().ok()
+        .and_then(|i| vec.get_mut(i))
+        .ok_or_else(|| format!("no index {last} in array"))?;
+      *slot = new;
+      Ok(())
+    }
+    _ => Err(format!("no map or array at {}", parent_keys.join("."))),
+  }
+}
+
+/// Remove the value at the path.
+fn delete_path(root: &mut Value, keys: &[&str]) -> Result<(), String> {
+  let Some((last, parent_keys)) = keys.split_last() else {
+    return Err("can't delete the whole memory".to_string())
+  };
+  let removed = match get_path_mut(root, parent_keys) {
+    Some(Value::Object(map)) => map.remove(*last).is_some(),
+    Some(Value::Array(vec)) => match last.parse::<usize>() {
+      Ok(i) if i < vec.len() => {
+        vec.remove(i);
+        true
+      }
+      _ => false,
+    },
+    _ => false,
+  };
+  if removed { Ok(()) } else { Err(format!("nothing at {}", keys.join("."))) }
+}
+
+/// The memory on the heap as JSON.
+fn memory_json() -> Result<Value, String> {
+  match peek_memory(|memory| serde_json::to_value(memory)) {
+    None => Err(NOT_LOADED.to_string()),
+    Some(Err(err)) => Err(format!("could not convert memory to JSON: {err}")),
+    Some(Ok(json)) => Ok(json),
+  }
+}
+
+/// Apply `edit` to the JSON form of the memory, check that it still makes a
+/// valid `Memory` and write it back.
+fn edit_memory(edit: impl FnOnce(&mut Value) -> Result<(), String>) -> String {
+  let mut json = match memory_json() {
+    Ok(json) => json,
+    Err(err) => return err,
+  };
+  if let Err(err) = edit(&mut json) {
+    return err;
+  }
+  let memory: Memory = match serde_json::from_value(json) {
+    Ok(memory) => memory,
+    Err(err) => return format!("invalid memory, nothing was changed: {err}"),
+  };
+  if replace_memory(memory) {
+    "ok".to_string()
+  } else {
+    NOT_LOADED.to_string()
+  }
+}
+
+/// Pretty print the memory at `path` as JSON.
+#[wasm_bindgen(js_name = memoryGet)]
+pub fn memory_get(path: JsString) -> String {
+  let path = String::from(path);
+  let keys = path_keys(&path);
+  let json = match memory_json() {
+    Ok(json) => json,
+    Err(err) => return err,
+  };
+  match get_path(&json, &keys) {
+    Some(value) => serde_json::to_string_pretty(value)
+      .unwrap_or_else(|err| format!("could not print JSON: {err}")),
+    None => format!("nothing at {path}"),
+  }
+}
+
+/// Set the memory at `path` to the JSON in `value`.
+#[wasm_bindgen(js_name = memorySet)]
+pub fn memory_set(path: JsString, value: JsString) -> String {
+  let path = String::from(path);
+  let new: Value = match serde_json::from_str(&String::from(value)) {
+    Ok(new) => new,
+    Err(err) => return format!("could not parse JSON: {err}"),
+  };
+  edit_memory(|json| set_path(json, &path_keys(&path), new))
+}
+
+/// Delete the entry at `path` in the memory.
+#[wasm_bindgen(js_name = memoryDelete)]
+pub fn memory_delete(path: JsString) -> String {
+  let path = String::from(path);
+  edit_memory(|json| delete_path(json, &path_keys(&path)))
+}
+
+/// Print the hit, miss and CPU stats for every cache used since the last
+/// global reset.
+#[wasm_bindgen(js_name = cacheStats)]
+pub fn cache_stats() -> String {
+  cache::stats_table()
+}
+
+/// Print how long creeps of each role lived, how much energy they delivered
+/// and how long they sat idle, for every creep that died since the last
+/// global reset.
+#[wasm_bindgen(js_name = creepOutcomes)]
+pub fn creep_outcomes() -> String {
+  outcomes::outcomes_table()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn edit_paths() {
+    let mut root = json!({ "creeps": { "Worker-1": "Idle" }, "list": [1, 2] });
+    assert_eq!(get_path(&root, &path_keys("creeps.Worker-1")), Some(&json!("Idle")));
+    assert_eq!(get_path(&root, &path_keys("list.1")), Some(&json!(2)));
+
+    set_path(&mut root, &path_keys("creeps.Worker-2"), json!("Idle")).unwrap();
+    set_path(&mut root, &path_keys("list.0"), json!(5)).unwrap();
+    assert!(set_path(&mut root, &path_keys("list.9"), json!(5)).is_err());
+    assert!(set_path(&mut root, &path_keys("missing.key"), json!(5)).is_err());
+
+    delete_path(&mut root, &path_keys("creeps.Worker-1")).unwrap();
+    assert!(delete_path(&mut root, &path_keys("creeps.Worker-1")).is_err());
+    assert!(delete_path(&mut root, &path_keys("")).is_err());
+    assert_eq!(root, json!({ "creeps": { "Worker-2": "Idle" }, "list": [5, 2] }));
+  }
+
+  #[test]
+  fn memory_json_round_trip() {
+    let mut memory = Memory::default();
+    memory.creeps.insert("EarlyWorker-0".to_string(),
+                         crate::creeps::early_worker::EarlyWorker::Idle.into());
+    let json = serde_json::to_value(&memory).unwrap();
+    assert_eq!(serde_json::from_value::<Memory>(json).unwrap(), memory);
+  }
+}
@@ -0,0 +1,135 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::{prelude::*, ConstructionSite, Creep, ErrorCode, Part, ResourceType};
+
+use super::role::Role;
+use super::worker::get_nearest_energy_supplier;
+use crate::body::BodyDesign;
+use crate::managers::construction;
+use crate::memory::Memory;
+use crate::storage::cbor;
+use crate::util::{energy_empty, energy_full, move_to_do, PrettyId};
+use crate::log_warn;
+
+/// Most WORK CARRY MOVE segments in a builder.
+const MAX_SEGMENTS: u32 = 16;
+
+/// The number of WORK CARRY MOVE segments we can afford for `max_energy`.
+pub fn builder_segments(max_energy: u32) -> u8 {
+  let segment = Part::Work.cost() + Part::Carry.cost() + Part::Move.cost();
+  (max_energy / segment).clamp(1, MAX_SEGMENTS) as u8
+}
+
+pub fn builder_design(work_parts: u8) -> BodyDesign {
+  BodyDesign::new()
+    .work(work_parts)
+    .carry(work_parts)
+    .r#move(work_parts)
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub enum BuilderState {
+  #[persist(0)] Idle,
+  #[persist(1)] Refueling(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<ConstructionSite>),
+  #[persist(2)] Building(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<ConstructionSite>),
+}
+
+impl BuilderState {
+  /// The site the builder is assigned to.
+  pub fn site(&self) -> Option<ObjectId<ConstructionSite>> {
+    match self {
+      BuilderState::Idle => None,
+      BuilderState::Refueling(site) | BuilderState::Building(site) => Some(*site),
+    }
+  }
+}
+
+/// Builds the construction sites it is given by the construction queue.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Builder {
+  #[persist(0)] pub state: BuilderState,
+  /// How many WORK parts it was spawned with, which is what it counts for
+  /// when assigned to a site.
+  #[persist(1)] pub work_parts: u8,
+}
+
+impl Builder {
+  pub fn new(work_parts: u8) -> Self {
+    Builder { state: BuilderState::Idle, work_parts }
+  }
+
+  fn find_site(&mut self, creep: &Creep, memory: &Memory) {
+    let site = creep.room()
+      .and_then(|room| construction::assign_site(&room, self.work_parts as u32, memory));
+    self.state = match site {
+      Some(site) if energy_empty(creep) => BuilderState::Refueling(site),
+      Some(site) => BuilderState::Building(site),
+      None => BuilderState::Idle,
+    };
+  }
+}
+
+impl Role for Builder {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    use BuilderState::*;
+    match &self.state {
+      Idle => self.find_site(creep, memory),
+      Refueling(site) if energy_full(creep) => self.state = Building(*site),
+      Building(site) if energy_empty(creep) => self.state = Refueling(*site),
+      _ => (),
+    }
+
+    match &self.state {
+      Idle => (),
+      Refueling(_) => {
+        let Some(room) = creep.room() else {
+          return
+        };
+        let Some(supplier) = get_nearest_energy_supplier(&room, creep.pos()) else {
+          return
+        };
+        move_to_do(creep, &supplier, 1, || {
+          match creep.withdraw(&supplier, ResourceType::Energy, None) {
+            Ok(()) | Err(ErrorCode::NotEnough) => (),
+            Err(err) => warn!("Builder {} could not withdraw energy: {err:?}", creep.id_str()),
+          }
+        });
+      }
+      Building(id) => {
+        let id = *id;
+        let Some(site) = id.resolve() else {
+          // it was finished, so the queue is out of date.
+          construction::release_site(id, self.work_parts as u32);
+          if let Some(room) = creep.room() {
+            construction::sites_changed(&room);
+          }
+          self.find_site(creep, memory);
+          if matches!(self.state, Building(_)) {
+            self.run(creep, memory);
+          }
+          return
+        };
+        move_to_do(creep, &site, 3, || {
+          log_warn!(creep.build(&site),
+                    err => "Builder {} could not build: {err:?}", creep.id_str());
+        });
+      }
+    }
+  }
+
+  fn on_death(&self, _name: &str, _memory: &mut Memory) {
+    if let Some(site) = self.state.site() {
+      construction::release_site(site, self.work_parts as u32);
+    }
+  }
+
+  fn is_idle(&self) -> bool {
+    matches!(self.state, BuilderState::Idle)
+  }
+}
@@ -0,0 +1,115 @@
+//! Creeps that take controllers in other rooms, for the targets in a
+//! colony's memory.
+
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::{prelude::*, game, Creep, Part, RoomName};
+use screeps::constants::MAX_CREEP_SIZE;
+
+use super::role::Role;
+use crate::body::BodyDesign;
+use crate::managers::colony;
+use crate::memory::Memory;
+use crate::storage::cbor;
+use crate::util::travel::{self, Travel};
+use crate::util::{move_to_do, PrettyId};
+use crate::log_warn;
+
+pub fn claimer_design() -> BodyDesign {
+  BodyDesign::new()
+    .claim(1)
+    .r#move(1)
+}
+
+/// CLAIM MOVE segments. It takes two CLAIM parts to do more than keep up
+/// with the reservation running down.
+pub fn reserver_segments(max_energy: u32) -> u8 {
+  let segment = Part::Claim.cost() + Part::Move.cost();
+  (max_energy / segment).clamp(1, (MAX_CREEP_SIZE / 2).min(4)) as u8
+}
+
+pub fn reserver_design(segments: u8) -> BodyDesign {
+  BodyDesign::new()
+    .claim(segments)
+    .r#move(segments)
+}
+
+/// Travels to `target` and moves over to its controller once it's there.
+/// Calls `op` with the controller when next to it.
+fn at_controller(
+  creep: &Creep, trip: &mut Travel, target: RoomName,
+  op: impl FnOnce(&screeps::StructureController)
+) {
+  if creep.pos().room_name() != target {
+    travel::travel_to(creep, trip, travel::room_center(target), 23);
+    return
+  }
+  let Some(controller) = game::rooms().get(target).and_then(|room| room.controller()) else {
+    warn!("Creep {} went to {target} which has no controller", creep.id_str());
+    return
+  };
+  move_to_do(creep, &controller, 1, || op(&controller));
+}
+
+/// Claims the controller of a room in its colony's targets.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Claimer {
+  #[persist(0, "cbor::room_name")]
+  pub home: RoomName,
+  #[persist(1, "cbor::room_name")]
+  pub target: RoomName,
+  #[serde(default)]
+  pub travel: Travel,
+}
+
+impl Role for Claimer {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    let mut claimed = false;
+    at_controller(creep, &mut self.travel, self.target, |controller| {
+      let reserved_by_others = controller.reservation()
+        .map_or(false, |reservation| reservation.username() != creep.owner().username());
+      if controller.my() {
+        claimed = true;
+      } else if reserved_by_others || controller.owner().is_some() {
+        // someone else's, so wear it down for the next claimer.
+        log_warn!(creep.attack_controller(controller),
+                  err => "Claimer {} could not attack controller: {err:?}", creep.id_str());
+      } else {
+        log_warn!(creep.claim_controller(controller),
+                  err => "Claimer {} could not claim: {err:?}", creep.id_str());
+      }
+    });
+    if claimed {
+      info!("claimed {} for {}", self.target, self.home);
+      colony::claimed(self.home, self.target, memory);
+      creep.suicide().ok();
+    }
+  }
+}
+
+/// Keeps the controller of a remote room reserved.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Reserver {
+  #[persist(0, "cbor::room_name")]
+  pub target: RoomName,
+  #[persist(1)] pub segments: u8,
+  #[serde(default)]
+  pub travel: Travel,
+}
+
+impl Role for Reserver {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    at_controller(creep, &mut self.travel, self.target, |controller| {
+      let theirs = controller.reservation()
+        .map_or(false, |reservation| reservation.username() != creep.owner().username());
+      if theirs {
+        log_warn!(creep.attack_controller(controller),
+                  err => "Reserver {} could not attack controller: {err:?}", creep.id_str());
+      } else {
+        log_warn!(creep.reserve_controller(controller),
+                  err => "Reserver {} could not reserve: {err:?}", creep.id_str());
+      }
+    });
+  }
+}
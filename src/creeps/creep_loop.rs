@@ -1,25 +1,29 @@
 use super::role::Role;
 use super::memory::{CreepMemory, RoleTag};
+use super::outcomes;
 use crate::memory::Memory;
 
 use log::*;
 use screeps::prelude::*;
-use screeps::{Room, game};
+use screeps::{Room, ResourceType, game};
 
 fn initial_creep_memory(room: &Room, tag: RoleTag) -> CreepMemory {
   todo!()
 }
 
 pub fn creep_loop(memory: &mut Memory) {
+  let time = game::time();
   for creep in game::creeps().values() {
+    let name = creep.name();
     if creep.spawning() {
       continue;
     }
-    let name = creep.name();
     debug!("running creep {}", name);
     if let Some(mem) = memory.creeps.get(&name) {
       let mut local = mem.clone();
       local.run(&creep, memory);
+      let energy = creep.store().get_used_capacity(Some(ResourceType::Energy));
+      outcomes::observe(memory, &name, time, energy, local.is_idle());
       memory.creeps.insert(name, local);
     } else {
       warn!("no memory for creep: {}", &name);
@@ -0,0 +1,143 @@
+//! Creeps that fight off hostiles in our rooms. Targets come from
+//! `managers::defense`.
+
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use screeps::{prelude::*, Creep, Part};
+use screeps::constants::MAX_CREEP_SIZE;
+
+use super::role::Role;
+use crate::body::BodyDesign;
+use crate::managers::defense;
+use crate::memory::Memory;
+use crate::util::{move_to_do, PrettyId};
+use crate::log_warn;
+
+/// The number of segments costing `segment_cost` and `segment_size` parts
+/// that we can afford for `max_energy`.
+fn segments_for(max_energy: u32, segment_cost: u32, segment_size: u32) -> u8 {
+  (max_energy / segment_cost).clamp(1, MAX_CREEP_SIZE / segment_size) as u8
+}
+
+/// TOUGH ATTACK MOVE MOVE segments, so it keeps full speed off road.
+pub fn defender_segments(max_energy: u32) -> u8 {
+  let cost = Part::Tough.cost() + Part::Attack.cost() + 2 * Part::Move.cost();
+  segments_for(max_energy, cost, 4)
+}
+
+pub fn defender_design(segments: u8) -> BodyDesign {
+  BodyDesign::new()
+    .tough(segments)
+    .attack(segments)
+    .r#move(2 * segments)
+}
+
+/// RANGED_ATTACK MOVE segments.
+pub fn ranged_defender_segments(max_energy: u32) -> u8 {
+  segments_for(max_energy, Part::RangedAttack.cost() + Part::Move.cost(), 2)
+}
+
+pub fn ranged_defender_design(segments: u8) -> BodyDesign {
+  BodyDesign::new()
+    .ranged_attack(segments)
+    .r#move(segments)
+}
+
+/// HEAL MOVE segments.
+pub fn healer_segments(max_energy: u32) -> u8 {
+  segments_for(max_energy, Part::Heal.cost() + Part::Move.cost(), 2)
+}
+
+pub fn healer_design(segments: u8) -> BodyDesign {
+  BodyDesign::new()
+    .heal(segments)
+    .r#move(segments)
+}
+
+/// Closes in on hostiles and attacks them.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Defender {
+  #[persist(0)] pub segments: u8,
+  /// Whether it had nothing to attack this tick.
+  #[serde(default)]
+  pub idle: bool,
+}
+
+impl Role for Defender {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    let target = creep.room().and_then(|room| defense::pick_target(&room, creep.pos()));
+    self.idle = target.is_none();
+    let Some(target) = target else {
+      return
+    };
+    move_to_do(creep, &target, 1, || {
+      log_warn!(creep.attack(&target),
+                err => "Defender {} could not attack: {err:?}", creep.id_str());
+    });
+  }
+
+  fn is_idle(&self) -> bool {
+    self.idle
+  }
+}
+
+/// Attacks hostiles from range.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct RangedDefender {
+  #[persist(0)] pub segments: u8,
+  /// Whether it had nothing to attack this tick.
+  #[serde(default)]
+  pub idle: bool,
+}
+
+impl Role for RangedDefender {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    let target = creep.room().and_then(|room| defense::pick_target(&room, creep.pos()));
+    self.idle = target.is_none();
+    let Some(target) = target else {
+      return
+    };
+    move_to_do(creep, &target, 3, || {
+      log_warn!(creep.ranged_attack(&target),
+                err => "Ranged defender {} could not attack: {err:?}", creep.id_str());
+    });
+  }
+
+  fn is_idle(&self) -> bool {
+    self.idle
+  }
+}
+
+/// Follows the defenders and heals whoever is the most hurt.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Healer {
+  #[persist(0)] pub segments: u8,
+  /// Whether it had nobody to heal this tick.
+  #[serde(default)]
+  pub idle: bool,
+}
+
+impl Role for Healer {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    let patient = creep.room().and_then(|room| defense::pick_patient(&room));
+    self.idle = patient.is_none();
+    let Some(patient) = patient else {
+      return
+    };
+    let range = creep.pos().get_range_to(patient.pos());
+    if range > 1 {
+      if range <= 3 {
+        log_warn!(creep.ranged_heal(&patient),
+                  err => "Healer {} could not heal at range: {err:?}", creep.id_str());
+      }
+      move_to_do(creep, &patient, 1, || ());
+    } else {
+      log_warn!(creep.heal(&patient),
+                err => "Healer {} could not heal: {err:?}", creep.id_str());
+    }
+  }
+
+  fn is_idle(&self) -> bool {
+    self.idle
+  }
+}
@@ -1,4 +1,5 @@
-use minicbor::{Encode, Decode};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use log::*;
 use screeps::constants::{ResourceType, ErrorCode};
 use screeps::{
@@ -16,20 +17,20 @@ use super::role::Role;
 use super::memory::RoleTag;
 use crate::managers::city::{current_role_count, place_spawn_extension};
 
-#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub enum EarlyWorker {
-  #[n(0)] Idle,
-  #[n(1)] Transfer(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(0)] Idle,
+  #[persist(1)] Transfer(
+    #[persist(0, "cbor::object_id")]
     ObjectId<EnergySink>),
-  #[n(2)] Upgrade(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(2)] Upgrade(
+    #[persist(0, "cbor::object_id")]
     ObjectId<StructureController>),
-  #[n(3)] Build(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(3)] Build(
+    #[persist(0, "cbor::object_id")]
     ObjectId<ConstructionSite>),
-  #[n(4)] Harvest(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(4)] Harvest(
+    #[persist(0, "cbor::object_id")]
     ObjectId<Source>),
 }
 
@@ -105,6 +106,10 @@ impl EarlyWorker {
 }
 
 impl Role for EarlyWorker {
+  fn is_idle(&self) -> bool {
+    matches!(self, EarlyWorker::Idle)
+  }
+
   fn run(&mut self, creep: &Creep, memory: &mut Memory) {
     use EarlyWorker::*;
     match self {
@@ -144,7 +149,7 @@ impl Role for EarlyWorker {
           self.run(creep, memory);
           return
         };
-        move_to_do(creep, &controller, 1, || {
+        move_to_do(creep, &controller, 3, || {
           log_warn!(creep.upgrade_controller(&controller),
                     err => "Creep could not upgrade controller: {err:?}");
         });
@@ -1,19 +1,22 @@
 use wasm_bindgen::prelude::*;
 use screeps::{
-  StructureSpawn, StructureExtension, Transferable, RoomObject,
+  StructureSpawn, StructureExtension, StructureStorage, StructureContainer, Transferable,
+  RoomObject,
   Structure, HasStore, Store,
 };
 
 /// Energy sink can be:
 /// - spawn
 /// - spawn extension
+/// - storage
+/// - the container next to the controller
 ///
 /// Should only wrap owned structures that are Transferable.
 #[wasm_bindgen]
 extern "C" {
   /// Object representing someplace a worker should transfer energy to.
   ///
-  /// Currently should only be a spawn.
+  /// Currently a spawn, extension, storage or controller container.
   #[wasm_bindgen(extends = RoomObject, extends = Structure)]
   #[derive(Clone, Debug)]
   pub type EnergySink;
@@ -38,6 +41,18 @@ impl From<StructureExtension> for EnergySink {
   }
 }
 
+impl From<StructureStorage> for EnergySink {
+  fn from(value: StructureStorage) -> Self {
+    JsValue::from(value).into()
+  }
+}
+
+impl From<StructureContainer> for EnergySink {
+  fn from(value: StructureContainer) -> Self {
+    JsValue::from(value).into()
+  }
+}
+
 impl Transferable for EnergySink {}
 
 impl HasStore for EnergySink {
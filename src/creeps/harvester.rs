@@ -1,11 +1,12 @@
-use minicbor::{Encode, Decode};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use log::*;
 use screeps::local::ObjectId;
 use screeps::look::{PositionedLookResult, self, LookResult};
 use screeps::{
   prelude::*, RoomXY, find, Position, StructureContainer, StructureLink,
   Room, RoomObject, Creep, Source, StructureController, ConstructionSite,
-  StructureSpawn, Terrain, StructureObject, StructureType, StoreObject,
+  StructureSpawn, Terrain, StructureObject, StructureType, StoreObject, Mineral,
   HasTypedId, HasNativeId, HasId, Resolvable, RoomCoordinate, Direction, StructureKeeperLair,
 };
 use screeps::constants::{ResourceType, ErrorCode};
@@ -14,8 +15,9 @@ use screeps::Path;
 use super::role::Role;
 use crate::log_warn;
 use crate::creeps::CreepMemory;
-use crate::memory::Memory;
+use crate::memory::{Memory, SourceMemory};
 use crate::storage::cache;
+use crate::managers::repair;
 use crate::util::{self, energy_empty, energy_full, move_to_do, PrettyId};
 use crate::storage::cbor;
 
@@ -64,7 +66,7 @@ mk_cache! {
 }
 
 pub fn num_assigned_harvesters(source: &Source, memory: &Memory) -> u8 {
-  source_num_harvesters::caches(&source.id(), |_| {
+  source_num_harvesters::caches_tagged(&source.id(), &[source.id().into()], |_| {
     let room = match source.room() {
       None => {
         warn!("Could not get room for source {}", source.id());
@@ -86,7 +88,7 @@ pub fn num_assigned_harvesters(source: &Source, memory: &Memory) -> u8 {
 /// Used to tell us that we have assigned a new harvester to a source or transfered
 /// one to or from it, and the number assigned will need to be recalculated.
 pub fn harvester_assignments_changed_for(source: &Source) {
-  source_num_harvesters::invalidate_now(&source.id())
+  cache::invalidate_tag(source.id());
 }
 
 // TODO: in the future, upgrade to use StoreObject instead of just
@@ -120,6 +122,19 @@ impl HarvestStorage {
         HarvestStorageId::Build(bldg.try_id().expect("construction site has no id")),
     }
   }
+
+  /// Whether harvesters can put `resource` in it. Links only take energy.
+  pub fn accepts(&self, resource: ResourceType) -> bool {
+    match self {
+      HarvestStorage::Link(_) => resource == ResourceType::Energy,
+      HarvestStorage::Container(_) => true,
+      HarvestStorage::Build(site) => match site.structure_type() {
+        StructureType::Link => resource == ResourceType::Energy,
+        StructureType::Container => true,
+        _ => false,
+      },
+    }
+  }
 }
 
 impl HarvestStorageId {
@@ -149,9 +164,14 @@ mk_cache! {
   source_nearby_storage lifetime 31 by ObjectId<Source> => Option<HarvestStorageId>
 }
 
-fn raw_nearby_storage(source: &Source) -> Option<HarvestStorageId> {
-  let pos = source.pos();
-  let struct_iter = pos.find_in_range(find::STRUCTURES, 3)
+mk_cache! {
+  mineral_nearby_storage lifetime 31 by ObjectId<Mineral> => Option<HarvestStorageId>
+}
+
+/// The closest storage within `range` of `pos` that takes `resource`, or a
+/// construction site for one.
+fn raw_nearby_storage(pos: Position, range: u8, resource: ResourceType) -> Option<HarvestStorageId> {
+  let struct_iter = pos.find_in_range(find::STRUCTURES, range)
     .into_iter()
     .filter_map(|building| match building {
       StructureObject::StructureLink(link) =>
@@ -160,60 +180,105 @@ fn raw_nearby_storage(source: &Source) -> Option<HarvestStorageId> {
         Some(HarvestStorage::Container(cont)),
       _ => None
     });
-  let site_iter = pos.find_in_range(find::CONSTRUCTION_SITES, 3)
+  let site_iter = pos.find_in_range(find::CONSTRUCTION_SITES, range)
     .into_iter()
     .map(|site| HarvestStorage::Build(site));
   struct_iter.chain(site_iter)
+    .filter(|bldg| bldg.accepts(resource))
     .min_by_key(|bldg| bldg.pos().get_range_to(pos))
     .map(|bldg| bldg.id())
 }
 
 fn nearby_storage(source: &Source) -> Option<HarvestStorage> {
   source_nearby_storage::caches(&source.id(), |_| {
-    raw_nearby_storage(source)
+    raw_nearby_storage(source.pos(), 3, ResourceType::Energy)
   }).and_then(|id| id.resolve())
 }
 
+/// Where a mineral miner puts what it mines: the container next to the
+/// mineral, or the site for it.
+pub fn nearby_mineral_storage(mineral: &Mineral) -> Option<HarvestStorage> {
+  mineral_nearby_storage::caches(&mineral.id(), |_| {
+    raw_nearby_storage(mineral.pos(), 1, mineral.mineral_type())
+  }).and_then(|id| id.resolve())
+}
+
+/// The container next to the source, where harvesters stand and haulers
+/// collect from.
+pub fn source_container(source: &Source) -> Option<StructureContainer> {
+  match nearby_storage(source) {
+    Some(HarvestStorage::Container(cont)) if cont.pos().is_near_to(source.pos()) => Some(cont),
+    _ => None,
+  }
+}
+
+/// The container next to the mineral, where the miner stands.
+pub fn mineral_container(mineral: &Mineral) -> Option<StructureContainer> {
+  match nearby_mineral_storage(mineral) {
+    Some(HarvestStorage::Container(cont)) => Some(cont),
+    _ => None,
+  }
+}
+
+/// Called by the memory GC when a source's memory is removed.
+pub fn source_removed(id: ObjectId<Source>, _memory: SourceMemory) {
+  cache::invalidate_tag(id);
+}
+
 /// Inform that the storage where energy for a given source should be deposited
 /// has been changed and that it will take effect next tick.
 pub fn have_updated_source_storage(source: &Source) {
   source_nearby_storage::invalidate_next_tick(&source.id());
 }
 
+/// Like `have_updated_source_storage`, for the container at a mineral.
+pub fn have_updated_mineral_storage(mineral: &Mineral) {
+  mineral_nearby_storage::invalidate_next_tick(&mineral.id());
+}
+
 mk_cache! {
-  source_keeper_cache lifetime 1000 by ObjectId<Source> => Option<ObjectId<StructureKeeperLair>>
+  persist source_keeper_cache lifetime 1000 by ObjectId<Source> => Option<ObjectId<StructureKeeperLair>>
+}
+
+/// The keeper lairs among some hostile structures.
+pub fn keeper_lairs(
+  structures: Vec<StructureObject>
+) -> impl Iterator<Item = StructureKeeperLair> {
+  structures.into_iter()
+    .filter_map(|structure| match structure {
+      StructureObject::StructureKeeperLair(lair) => Some(lair),
+      _ => None,
+    })
 }
 
 pub fn lair_for_source(source: &Source) -> Option<ObjectId<StructureKeeperLair>> {
   source_keeper_cache::caches(&source.id(), |_| {
     let pos = source.pos();
-    let lair = pos.find_in_range(find::HOSTILE_STRUCTURES, 5)
-      .into_iter()
-      .filter_map(|structure| match structure {
-        StructureObject::StructureKeeperLair(lair) => Some(lair.id()),
-        _ => None,
-      })
-      .nth(0);
-    lair
+    keeper_lairs(pos.find_in_range(find::HOSTILE_STRUCTURES, 5))
+      .next()
+      .map(|lair| lair.id())
   })
 }
 
-#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub enum HarvesterState {
-  #[n(0)] Harvesting,
-  #[n(1)] Depositing
+  #[persist(0)] Harvesting,
+  #[persist(1)] Depositing
 }
 
-#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub struct Harvester {
-  #[n(0)] pub state: HarvesterState,
-  #[n(1)] #[cbor(with = "cbor::object_id")]
-  pub source: ObjectId<Source>
+  #[persist(0)] pub state: HarvesterState,
+  #[persist(1, "cbor::object_id")]
+  pub source: ObjectId<Source>,
+  /// The container next to the source that we stand on while harvesting.
+  #[persist(rebuild = "Harvester::survey_spot")] #[serde(default)]
+  pub spot: Option<ObjectId<StructureContainer>>,
 }
 
-/// This only works within the room.
+/// This only works within the room; sources in other rooms are mined by the
+/// operations in `managers::remote`.
 fn find_source_needing_harvester(room: &Room) -> Option<Source> {
-  // TODO: plan out infrastructure to allow this to work across all rooms.
   room.find(find::SOURCES_ACTIVE, None)
     .into_iter()
     .filter(|source| !util::are_hostiles_near(room, source.pos(), 5))
@@ -233,10 +298,18 @@ mk_cache! {
 impl Harvester {
   /// Create a new harvester for this source.
   pub fn new(source: &Source) -> Self {
-    Harvester {
+    let mut harvester = Harvester {
       state: HarvesterState::Harvesting,
       source: source.id(),
-    }
+      spot: None,
+    };
+    harvester.spot = Harvester::survey_spot(&harvester);
+    harvester
+  }
+
+  /// Find the container next to our source, if there is one.
+  fn survey_spot(&self) -> Option<ObjectId<StructureContainer>> {
+    source_container(&self.source.resolve()?).map(|cont| cont.id())
   }
 }
 
@@ -263,19 +336,23 @@ impl Role for Harvester {
 
     match &self.state {
       Harvesting => {
-        move_to_do(creep, &source, 1, || {
+        let harvest = || {
           log_warn!(creep.harvest(&source), err =>
                     "Harvester {} couldn't harvest because: {err:?}", creep.id_str()
           )
-        })
+        };
+        match self.spot.and_then(|id| id.resolve()) {
+          Some(cont) => move_to_do(creep, &cont, 0, harvest),
+          None => move_to_do(creep, &source, 1, harvest),
+        }
       }
       Deposit => {
         // TODO: handle full storage.
         match nearby_storage(&source) {
-          // TODO: do we need to periodically repair the container? I think so.
           Some(HarvestStorage::Container(cont)) => {
             move_to_do(creep, &cont, 1, || {
-              if cont.hits_max() / cont.hits() >= 2 {
+              // the harvester is already here, so it looks after its own container.
+              if repair::needs_repair(cont.hits(), cont.hits_max()) {
                 debug!("Repairing container bc max hits {} hits {}", cont.hits_max(), cont.hits());
                 log_warn!(creep.repair(&cont), err =>
                           "Harvester {} failed repair because: {err:?}", creep.id_str());
@@ -312,4 +389,8 @@ impl Role for Harvester {
       }
     }
   }
+
+  fn on_death(&self, _name: &str, _memory: &mut Memory) {
+    cache::invalidate_tag(self.source);
+  }
 }
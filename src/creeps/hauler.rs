@@ -0,0 +1,290 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::pathfinder;
+use screeps::{
+  prelude::*, find, game, Creep, Source, Room, Position, StructureObject, ResourceType,
+  ErrorCode,
+};
+use screeps::constants::{CARRY_CAPACITY, ENERGY_REGEN_TIME, MAX_CREEP_SIZE};
+use wasm_bindgen::JsValue;
+
+use super::role::Role;
+use super::energy_sink::EnergySink;
+use super::harvester::{self, source_container};
+use super::upgrader;
+use crate::body::BodyDesign;
+use crate::creeps::CreepMemory;
+use crate::memory::Memory;
+use crate::storage::cache;
+use crate::storage::cbor;
+use crate::util::{self, energy_empty, energy_full, move_to_do, PrettyId};
+use crate::{log_warn, mk_cache};
+
+// See "Hauler Math" in PLANNING.md.
+
+/// Ticks per tile we want a loaded hauler to move at.
+pub const STEP_TIME: u32 = 1;
+/// Both CARRY and MOVE cost 50.
+const PART_COST: u32 = 50;
+
+/// The body of a hauler and how much it carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HaulerPlan {
+  pub carry_parts: u32,
+  pub move_parts: u32,
+  pub capacity: u32,
+}
+
+impl HaulerPlan {
+  /// The biggest hauler we can build for `max_cost` that still moves one tile
+  /// every `step_time` ticks when full.
+  pub fn for_cost(max_cost: u32, step_time: u32) -> HaulerPlan {
+    let carry_ratio = step_time;
+    let segment_size = carry_ratio + 1;
+    let total_segments = (max_cost / (segment_size * PART_COST))
+      .min(MAX_CREEP_SIZE / segment_size)
+      .max(1);
+    let carry_parts = carry_ratio * total_segments;
+    HaulerPlan {
+      carry_parts,
+      move_parts: total_segments,
+      capacity: CARRY_CAPACITY * carry_parts,
+    }
+  }
+
+  pub fn design(&self) -> BodyDesign {
+    BodyDesign::new()
+      .carry(self.carry_parts as u8)
+      .r#move(self.move_parts as u8)
+  }
+}
+
+/// Energy moved per tick by haulers carrying `capacity` between them, going
+/// `distance` tiles each way.
+pub fn hauled_per_tick(capacity: u32, distance: u32, step_time: u32) -> f64 {
+  let duration = distance.max(1) * step_time;
+  // they have to walk back empty before the next load.
+  capacity as f64 / (2 * duration) as f64
+}
+
+/// Whether haulers carrying `capacity` between them fall short of `income`.
+pub fn needs_more_hauling(capacity: u32, distance: u32, step_time: u32, income: f64) -> bool {
+  hauled_per_tick(capacity, distance, step_time) < income
+}
+
+/// How much the living creeps among `names` can carry between them.
+pub fn carry_capacity<'a>(names: impl Iterator<Item = &'a String>) -> u32 {
+  let creeps = game::creeps();
+  names
+    .filter_map(|name| creeps.get(name.clone()))
+    .map(|creep| creep.store().get_capacity(None))
+    .sum()
+}
+
+/// The body for a hauler when the room can spend `max_energy` on it. This is
+/// `city::spawn_budget`, the same budget spawning checks it against.
+pub fn hauler_design(max_energy: u32) -> BodyDesign {
+  HaulerPlan::for_cost(max_energy, STEP_TIME).design()
+}
+
+/// Energy per tick a source regenerates.
+fn source_income(source: &Source) -> f64 {
+  source.energy_capacity() as f64 / ENERGY_REGEN_TIME as f64
+}
+
+/// Where haulers drop energy off, for measuring how far they travel.
+pub fn drop_off_pos(room: &Room) -> Option<Position> {
+  if let Some(storage) = room.storage() {
+    return Some(storage.pos())
+  }
+  room.find(find::MY_SPAWNS, None).first().map(|spawn| spawn.pos())
+}
+
+mk_cache! {
+  source_haul_distance lifetime 1500 by ObjectId<Source> => Option<u32>
+}
+
+/// The path length between the container at a source and the drop off.
+fn haul_distance(source: &Source) -> Option<u32> {
+  let room = source.room()?;
+  let tags = [source.id().into(), room.name().into()];
+  source_haul_distance::caches_tagged(&source.id(), &tags, |_| {
+    let container = source_container(source)?;
+    let drop_off = drop_off_pos(&room)?;
+    let result = pathfinder::search(container.pos(), drop_off, 1, Some(util::local_search_opts()));
+    if result.incomplete() {
+      warn!("no path from the container at source {} to the drop off", source.id_str());
+      return None
+    }
+    Some(result.path().len() as u32)
+  })
+}
+
+mk_cache! {
+  source_hauler_capacity lifetime 30 by ObjectId<Source> => u32
+}
+
+/// How much the haulers assigned to `source` carry between them, going by
+/// the bodies they were actually spawned with.
+pub fn assigned_hauler_capacity(source: &Source, memory: &Memory) -> u32 {
+  source_hauler_capacity::caches_tagged(&source.id(), &[source.id().into()], |_| {
+    let id = source.id();
+    let names = memory.creeps.iter()
+      .filter(|(_, mem)| matches!(mem, CreepMemory::Hauler(hauler) if hauler.source == id))
+      .map(|(name, _)| name);
+    carry_capacity(names)
+  })
+}
+
+/// A source in the room whose container needs another hauler to keep up with
+/// the harvesters.
+pub fn source_needing_hauler(room: &Room, memory: &Memory) -> Option<Source> {
+  room.find(find::SOURCES, None)
+    .into_iter()
+    .filter(|source| harvester::num_assigned_harvesters(source, memory) > 0)
+    .find(|source| {
+      let Some(distance) = haul_distance(source) else {
+        return false
+      };
+      let capacity = assigned_hauler_capacity(source, memory);
+      needs_more_hauling(capacity, distance, STEP_TIME, source_income(source))
+    })
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub enum HaulerState {
+  #[persist(0)] Idle,
+  #[persist(1)] Collecting,
+  #[persist(2)] Delivering(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<EnergySink>),
+}
+
+/// Moves energy from the container at its source to spawns, extensions and
+/// storage.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Hauler {
+  #[persist(0)] pub state: HaulerState,
+  #[persist(1, "cbor::object_id")]
+  pub source: ObjectId<Source>,
+}
+
+/// The closest spawn or extension that needs energy, then the controller's
+/// link or container, then the storage.
+pub fn find_energy_sink(creep: &Creep) -> Option<EnergySink> {
+  let room = creep.room()?;
+  let needs_energy = |store: screeps::Store| {
+    store.get_free_capacity(Some(ResourceType::Energy)) > 0
+  };
+  let closest = room.find(find::MY_STRUCTURES, None)
+    .into_iter()
+    .filter_map(|structure| match structure {
+      StructureObject::StructureSpawn(spawn) if needs_energy(spawn.store()) =>
+        Some(EnergySink::from(spawn)),
+      StructureObject::StructureExtension(ext) if needs_energy(ext.store()) =>
+        Some(EnergySink::from(ext)),
+      _ => None,
+    })
+    .min_by_key(|sink| sink.pos().get_range_to(creep.pos()));
+  closest
+    .or_else(|| upgrader::controller_supplier(&room)
+             .filter(|supplier| needs_energy(supplier.store()))
+             .map(|supplier| JsValue::from(supplier).into()))
+    .or_else(|| room.storage()
+             .filter(|storage| needs_energy(storage.store()))
+             .map(EnergySink::from))
+}
+
+impl Hauler {
+  pub fn new(source: &Source) -> Self {
+    Hauler {
+      state: HaulerState::Idle,
+      source: source.id(),
+    }
+  }
+
+  fn deliver(&mut self, creep: &Creep) {
+    self.state = match find_energy_sink(creep) {
+      Some(sink) => HaulerState::Delivering(sink.id()),
+      None => HaulerState::Idle,
+    };
+  }
+}
+
+impl Role for Hauler {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    use HaulerState::*;
+    match &self.state {
+      Idle if energy_empty(creep) => self.state = Collecting,
+      Idle => self.deliver(creep),
+      Collecting if energy_full(creep) => self.deliver(creep),
+      Delivering(_) if energy_empty(creep) => self.state = Collecting,
+      _ => (),
+    }
+
+    match &self.state {
+      Idle => (),
+      Collecting => {
+        let Some(container) = self.source.resolve().and_then(|s| source_container(&s)) else {
+          self.state = Idle;
+          return
+        };
+        move_to_do(creep, &container, 1, || {
+          match creep.withdraw(&container, ResourceType::Energy, None) {
+            Ok(()) => (),
+            // wait by the container for the harvesters to fill it.
+            Err(ErrorCode::NotEnough) => (),
+            Err(err) => warn!("Hauler {} could not withdraw energy: {err:?}", creep.id_str()),
+          }
+        });
+      }
+      Delivering(id) => {
+        let sink = id.resolve()
+          .filter(|sink| sink.store().get_free_capacity(Some(ResourceType::Energy)) > 0);
+        let Some(sink) = sink else {
+          self.deliver(creep);
+          if matches!(self.state, Delivering(_)) {
+            self.run(creep, memory);
+          }
+          return
+        };
+        move_to_do(creep, &sink, 1, || {
+          log_warn!(creep.transfer(&sink, ResourceType::Energy, None),
+                    err => "Hauler {} could not deliver energy: {err:?}", creep.id_str());
+        });
+      }
+    }
+  }
+
+  fn on_death(&self, _name: &str, _memory: &mut Memory) {
+    cache::invalidate_tag(self.source);
+  }
+
+  fn is_idle(&self) -> bool {
+    matches!(self.state, HaulerState::Idle)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn plan_follows_hauler_math() {
+    let plan = HaulerPlan::for_cost(550, 1);
+    // five CARRY+MOVE segments.
+    assert_eq!(plan, HaulerPlan { carry_parts: 5, move_parts: 5, capacity: 250 });
+    // 250 energy every 2 * 25 ticks.
+    assert_eq!(hauled_per_tick(plan.capacity, 25, 1), 5.0);
+    // capped by the creep size rather than the cost.
+    let big = HaulerPlan::for_cost(10_000, 1);
+    assert_eq!(big.carry_parts + big.move_parts, MAX_CREEP_SIZE);
+    // two small haulers do the work of one big one.
+    assert!(needs_more_hauling(150, 25, 1, 5.0));
+    assert!(!needs_more_hauling(150 + 100, 25, 1, 5.0));
+    // a slower hauler carries more per MOVE.
+    assert_eq!(HaulerPlan::for_cost(300, 2).carry_parts, 4);
+  }
+}
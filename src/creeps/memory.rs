@@ -1,15 +1,26 @@
 use minicbor::{Encode, Decode};
+use serde::{Serialize, Deserialize};
+use persist_memory::Persist;
 use std::hash::Hash;
 use screeps::Creep;
 use super::role::Role;
 use super::harvester::*;
+use super::hauler::*;
+use super::upgrader::*;
+use super::builder::*;
+use super::repairer::*;
+use super::defender::*;
+use super::claimer::*;
+use super::remote::*;
+use super::scout::*;
+use super::mineral::*;
 use super::worker::*;
 use super::early_worker::*;
 use crate::memory::Memory;
 
 macro_rules! gen_roles {
   ($($n:literal => $t:ident)*) => {
-    #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone, Encode, Decode)]
+    #[derive(PartialEq, Eq, Hash, Debug, Copy, Clone, Encode, Decode, Serialize, Deserialize)]
     #[cbor(index_only)]
     #[repr(u8)]
     pub enum RoleTag {
@@ -26,12 +37,28 @@ macro_rules! gen_roles {
           ),*
         }
       }
+
+      fn on_death(&self, name: &str, memory: &mut Memory) {
+        match self {
+          $(
+            CreepMemory::$t(ref mem) => mem.on_death(name, memory)
+          ),*
+        }
+      }
+
+      fn is_idle(&self) -> bool {
+        match self {
+          $(
+            CreepMemory::$t(ref mem) => mem.is_idle()
+          ),*
+        }
+      }
     }
 
-    #[derive(Clone, PartialEq, Debug, Encode, Decode)]
+    #[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
     pub enum CreepMemory {
       $(
-        #[n($n)] $t(#[n(0)] $t)
+        #[persist($n)] $t(#[persist(0)] $t)
       ),*
     }
 
@@ -59,4 +86,18 @@ gen_roles! {
   0 => Harvester
   1 => Worker
   2 => EarlyWorker
+  3 => Hauler
+  4 => Upgrader
+  5 => Builder
+  6 => Repairer
+  7 => Defender
+  8 => RangedDefender
+  9 => Healer
+  10 => Claimer
+  11 => Reserver
+  12 => RemoteHarvester
+  13 => RemoteHauler
+  14 => Scout
+  15 => MineralMiner
+  16 => MineralHauler
 }
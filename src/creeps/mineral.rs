@@ -0,0 +1,260 @@
+//! Mining the room's mineral once it has an extractor, and hauling what's
+//! mined to the terminal or storage.
+
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::{
+  prelude::*, find, Creep, ErrorCode, Mineral, ResourceType, Room, StructureExtractor,
+  StructureObject,
+};
+
+use super::role::Role;
+use super::harvester::mineral_container;
+use super::hauler::{HaulerPlan, STEP_TIME};
+use super::worker::Supplier;
+use crate::body::BodyDesign;
+use crate::creeps::CreepMemory;
+use crate::managers::city;
+use crate::memory::Memory;
+use crate::storage::cbor;
+use crate::util::{move_to_do, PrettyId};
+use crate::log_warn;
+
+/// Most WORK parts worth putting on a mineral miner, since the extractor
+/// only lets it harvest once every few ticks anyway.
+const MAX_MINERAL_WORK: u8 = 16;
+/// Most energy spent on a mineral hauler. Minerals come in slowly, so a
+/// small one keeps up.
+const MINERAL_HAULER_COST: u32 = 800;
+
+/// Most WORK parts a mineral miner built for `max_energy` can have, with a
+/// MOVE for every four WORK since it only walks to the mineral once.
+pub fn mineral_miner_work(max_energy: u32) -> u8 {
+  (1..=MAX_MINERAL_WORK)
+    .rev()
+    .find(|work| mineral_miner_design(*work).base_cost() <= max_energy)
+    .unwrap_or(1)
+}
+
+pub fn mineral_miner_design(work_parts: u8) -> BodyDesign {
+  BodyDesign::new()
+    .work(work_parts)
+    .r#move(work_parts.div_ceil(4))
+}
+
+pub fn mineral_hauler_design(max_energy: u32) -> BodyDesign {
+  HaulerPlan::for_cost(max_energy.min(MINERAL_HAULER_COST), STEP_TIME).design()
+}
+
+/// The extractor built on the mineral, if there is one.
+pub fn extractor(mineral: &Mineral) -> Option<StructureExtractor> {
+  mineral.pos().find_in_range(find::MY_STRUCTURES, 0)
+    .into_iter()
+    .find_map(|structure| match structure {
+      StructureObject::StructureExtractor(extractor) => Some(extractor),
+      _ => None,
+    })
+}
+
+/// Where mined minerals go: the terminal while it has room, then the storage.
+fn mineral_drop_off(room: &Room, resource: ResourceType) -> Option<Supplier> {
+  let has_room = |store: screeps::Store| store.get_free_capacity(Some(resource)) > 0;
+  room.terminal()
+    .filter(|terminal| has_room(terminal.store()))
+    .map(Supplier::from)
+    .or_else(|| room.storage()
+             .filter(|storage| has_room(storage.store()))
+             .map(Supplier::from))
+}
+
+/// The next mineral miner or hauler `room` should spawn, once its mineral has
+/// an extractor and a container.
+pub fn next_mineral_creep(room: &Room, memory: &Memory) -> Option<CreepMemory> {
+  if room.terminal().is_none() && room.storage().is_none() {
+    return None
+  }
+  room.find(find::MINERALS, None)
+    .into_iter()
+    .filter(|mineral| extractor(mineral).is_some())
+    .find_map(|mineral| {
+      let container = mineral_container(&mineral)?;
+      let id = mineral.id();
+      let (miners, haulers) = memory.creeps.values()
+        .fold((0, 0), |(miners, haulers), mem| match mem {
+          CreepMemory::MineralMiner(miner) if miner.mineral == id => (miners + 1, haulers),
+          CreepMemory::MineralHauler(hauler) if hauler.mineral == id => (miners, haulers + 1),
+          _ => (miners, haulers),
+        });
+      let mined = container.store().get_used_capacity(Some(mineral.mineral_type()));
+      if miners == 0 && mineral.mineral_amount() > 0 {
+        Some(MineralMiner::new(&mineral, city::spawn_budget(room)).into())
+      } else if haulers == 0 && (miners > 0 || mined > 0) {
+        Some(MineralHauler::new(&mineral).into())
+      } else {
+        None
+      }
+    })
+}
+
+/// Stands on the container by the mineral and harvests whenever the
+/// extractor is off cooldown. It has no CARRY, so what it mines drops into
+/// the container.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct MineralMiner {
+  #[persist(0, "cbor::object_id")]
+  pub mineral: ObjectId<Mineral>,
+  #[persist(1)] pub work_parts: u8,
+}
+
+impl MineralMiner {
+  pub fn new(mineral: &Mineral, max_energy: u32) -> Self {
+    MineralMiner {
+      mineral: mineral.id(),
+      work_parts: mineral_miner_work(max_energy),
+    }
+  }
+}
+
+impl Role for MineralMiner {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    let Some(mineral) = self.mineral.resolve() else {
+      return
+    };
+    match mineral_container(&mineral) {
+      Some(container) if creep.pos() != container.pos() => {
+        move_to_do(creep, &container, 0, || ());
+        return
+      }
+      None if !creep.pos().is_near_to(mineral.pos()) => {
+        move_to_do(creep, &mineral, 1, || ());
+        return
+      }
+      _ => (),
+    }
+    let Some(extractor) = extractor(&mineral) else {
+      return
+    };
+    // harvesting on cooldown only wastes the intent.
+    if extractor.cooldown() > 0 || mineral.mineral_amount() == 0 {
+      return
+    }
+    match creep.harvest(&mineral) {
+      Ok(()) | Err(ErrorCode::Tired) | Err(ErrorCode::NotEnough) => (),
+      Err(err) => warn!("Mineral miner {} could not harvest: {err:?}", creep.id_str()),
+    }
+  }
+}
+
+/// Whether a mineral hauler carrying `carried` with `free` room left should
+/// take it to the drop off, given what's left in the container and in the
+/// mineral itself.
+fn should_deliver(carried: u32, free: u32, container_left: u32, mineral_left: u32) -> bool {
+  // while the mineral regenerates nothing more is coming, so bring in the rest.
+  free == 0 || (carried > 0 && container_left == 0 && mineral_left == 0)
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub enum MineralHaulerState {
+  #[persist(0)] Collecting,
+  #[persist(1)] Delivering,
+}
+
+/// Carries minerals from the container by the mineral to the terminal or
+/// storage.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct MineralHauler {
+  #[persist(0)] pub state: MineralHaulerState,
+  #[persist(1, "cbor::object_id")]
+  pub mineral: ObjectId<Mineral>,
+}
+
+impl MineralHauler {
+  pub fn new(mineral: &Mineral) -> Self {
+    MineralHauler {
+      state: MineralHaulerState::Collecting,
+      mineral: mineral.id(),
+    }
+  }
+
+  fn collect(&self, creep: &Creep, mineral: &Mineral) {
+    let Some(container) = mineral_container(mineral) else {
+      return
+    };
+    move_to_do(creep, &container, 1, || {
+      match creep.withdraw(&container, mineral.mineral_type(), None) {
+        Ok(()) => (),
+        // wait by the container for the miner to fill it.
+        Err(ErrorCode::NotEnough) => (),
+        Err(err) => warn!("Mineral hauler {} could not withdraw: {err:?}", creep.id_str()),
+      }
+    });
+  }
+
+  fn deliver(&self, creep: &Creep) {
+    let Some(room) = creep.room() else {
+      return
+    };
+    let Some(resource) = creep.store().store_types().into_iter().next() else {
+      return
+    };
+    let Some(drop_off) = mineral_drop_off(&room, resource) else {
+      return
+    };
+    move_to_do(creep, &drop_off, 1, || {
+      log_warn!(creep.transfer(&drop_off, resource, None),
+                err => "Mineral hauler {} could not deliver {resource:?}: {err:?}",
+                creep.id_str());
+    });
+  }
+}
+
+impl Role for MineralHauler {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    use MineralHaulerState::*;
+    let Some(mineral) = self.mineral.resolve() else {
+      return
+    };
+    let store = creep.store();
+    let carried = store.get_used_capacity(None);
+    match self.state {
+      Collecting => {
+        let container_left = mineral_container(&mineral)
+          .map_or(0, |cont| cont.store().get_used_capacity(Some(mineral.mineral_type())));
+        let free = store.get_free_capacity(None).max(0) as u32;
+        if should_deliver(carried, free, container_left, mineral.mineral_amount()) {
+          self.state = Delivering;
+        }
+      }
+      Delivering if carried == 0 => self.state = Collecting,
+      Delivering => (),
+    }
+    match self.state {
+      Collecting => self.collect(creep, &mineral),
+      Delivering => self.deliver(creep),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mineral_creeps_fit_the_energy() {
+    // WORK WORK MOVE.
+    assert_eq!(mineral_miner_work(250), 2);
+    assert_eq!(mineral_miner_work(2300), 16);
+    assert_eq!(mineral_miner_design(5).size(), 7);
+    for budget in [250, 1800, 2300] {
+      assert!(mineral_miner_design(mineral_miner_work(budget)).fits(budget));
+      assert!(mineral_hauler_design(budget).fits(budget));
+    }
+
+    assert!(should_deliver(400, 0, 100, 5000));
+    assert!(!should_deliver(200, 200, 0, 5000));
+    assert!(should_deliver(200, 200, 0, 0));
+    assert!(!should_deliver(0, 400, 0, 0));
+  }
+}
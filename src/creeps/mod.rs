@@ -3,9 +3,35 @@ pub mod creep_loop;
 pub mod memory;
 pub mod worker;
 pub mod harvester;
+pub mod hauler;
+pub mod upgrader;
+pub mod builder;
+pub mod repairer;
+pub mod defender;
+pub mod claimer;
+pub mod remote;
+pub mod scout;
+pub mod mineral;
 pub mod early_worker;
 pub mod energy_sink;
+pub mod outcomes;
 
 pub use creep_loop::*;
 pub use role::Role;
 pub use memory::{RoleTag, CreepMemory};
+
+use js_sys::{JsString, Reflect};
+use screeps::game;
+use crate::memory::Memory;
+
+/// Called once a creep has died and its memory has been removed.
+pub fn creep_removed(name: &str, creep_memory: CreepMemory, memory: &mut Memory) {
+  // the game still keeps a JS `Memory.creeps` entry for creeps that used it.
+  if let Ok(js_creeps) = Reflect::get(&screeps::memory::ROOT, &JsString::from("creeps")) {
+    if js_creeps.is_object() {
+      let _ = Reflect::delete_property(&js_creeps.into(), &JsString::from(name));
+    }
+  }
+  outcomes::record_death(memory, name, creep_memory.tag(), game::time());
+  creep_memory.on_death(name, memory);
+}
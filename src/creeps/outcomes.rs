@@ -0,0 +1,125 @@
+//! Lifetime stats for each creep, totalled up per role when it dies.
+//!
+//! These live on the heap only, so they start over after a global reset. The
+//! stats of living creeps are kept in `Memory::creep_stats`.
+//! Energy delivered is counted as any drop in the energy a creep carries
+//! between ticks, so it includes energy spent building and upgrading.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use serde::{Serialize, Deserialize};
+
+use super::RoleTag;
+use crate::memory::Memory;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CreepStats {
+  /// The first tick this global saw the creep.
+  pub first_seen: u32,
+  pub delivered: u32,
+  pub idle_ticks: u32,
+  last_energy: u32,
+}
+
+/// The totals for every creep of a role that has died since the last reset.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct RoleOutcome {
+  pub deaths: u32,
+  pub lifetime_ticks: u32,
+  pub delivered: u32,
+  pub idle_ticks: u32,
+}
+
+thread_local! {
+  static OUTCOMES: RefCell<HashMap<RoleTag, RoleOutcome>> = RefCell::new(HashMap::new());
+}
+
+impl CreepStats {
+  fn observe(&mut self, energy: u32, idle: bool) {
+    self.delivered += self.last_energy.saturating_sub(energy);
+    self.last_energy = energy;
+    if idle {
+      self.idle_ticks += 1;
+    }
+  }
+}
+
+impl RoleOutcome {
+  fn add(&mut self, stats: &CreepStats, died_at: u32) {
+    self.deaths += 1;
+    self.lifetime_ticks += died_at.saturating_sub(stats.first_seen);
+    self.delivered += stats.delivered;
+    self.idle_ticks += stats.idle_ticks;
+  }
+}
+
+/// Update the stats of a living creep after it has run this tick.
+pub fn observe(memory: &mut Memory, name: &str, time: u32, energy: u32, idle: bool) {
+  memory.creep_stats
+    .entry(name.to_string())
+    .or_insert(CreepStats { first_seen: time, last_energy: energy, ..CreepStats::default() })
+    .observe(energy, idle);
+}
+
+/// Add the stats of a dead creep to the totals for its role.
+pub fn record_death(memory: &mut Memory, name: &str, role: RoleTag, time: u32) {
+  let Some(stats) = memory.creep_stats.remove(name) else {
+    return
+  };
+  info!("{name} died after {} ticks, delivering {} energy and idling {} ticks",
+        time.saturating_sub(stats.first_seen), stats.delivered, stats.idle_ticks);
+  OUTCOMES.with(|outcomes| {
+    outcomes.borrow_mut().entry(role).or_default().add(&stats, time);
+  })
+}
+
+/// A table of the outcomes for every role that has had a creep die.
+pub fn outcomes_table() -> String {
+  let mut rows: Vec<(RoleTag, RoleOutcome)> = OUTCOMES.with(|outcomes| {
+    outcomes.borrow().iter().map(|(role, outcome)| (*role, *outcome)).collect()
+  });
+  rows.sort_by_key(|(role, _)| *role as u8);
+  format_outcomes(&rows)
+}
+
+fn format_outcomes(rows: &[(RoleTag, RoleOutcome)]) -> String {
+  let mut out = format!("{:<12} {:>7} {:>9} {:>11} {:>6}\n",
+                        "role", "deaths", "avg life", "energy/life", "idle%");
+  for (role, outcome) in rows {
+    let deaths = outcome.deaths.max(1) as f64;
+    let idle = if outcome.lifetime_ticks == 0 {
+      0.0
+    } else {
+      100.0 * outcome.idle_ticks as f64 / outcome.lifetime_ticks as f64
+    };
+    out.push_str(&format!("{:<12} {:>7} {:>9.0} {:>11.0} {:>6.1}\n",
+                          format!("{role:?}"), outcome.deaths,
+                          outcome.lifetime_ticks as f64 / deaths,
+                          outcome.delivered as f64 / deaths, idle));
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn outcomes_total_creep_stats() {
+    let mut memory = Memory::default();
+    for (time, energy, idle) in [(10, 50, false), (11, 20, false), (12, 0, true), (13, 50, false)] {
+      observe(&mut memory, "Worker-1", time, energy, idle);
+    }
+    record_death(&mut memory, "Worker-1", RoleTag::Worker, 20);
+    assert!(memory.creep_stats.is_empty());
+    // unknown creeps are ignored.
+    record_death(&mut memory, "Worker-2", RoleTag::Worker, 20);
+    let outcome = OUTCOMES.with(|outcomes| outcomes.borrow()[&RoleTag::Worker]);
+    assert_eq!(outcome, RoleOutcome { deaths: 1, lifetime_ticks: 10, delivered: 50, idle_ticks: 1 });
+    let table = outcomes_table();
+    assert_eq!(table.lines().count(), 2);
+    assert!(table.contains("Worker"));
+  }
+}
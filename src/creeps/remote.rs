@@ -0,0 +1,223 @@
+//! Creeps for the remote mining operations in `managers::remote`.
+
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::{
+  prelude::*, find, game, Creep, ErrorCode, Position, ResourceType, RoomName, Source,
+  StructureType,
+};
+
+use super::role::Role;
+use super::harvester::source_container;
+use super::hauler;
+use crate::body::BodyDesign;
+use crate::managers::repair;
+use crate::memory::{Memory, RemoteOperation};
+use crate::storage::cbor;
+use crate::util::{energy_empty, energy_full, move_to_do, PrettyId};
+use crate::util::travel::{self, Travel};
+use crate::log_warn;
+
+/// WORK parts it takes to empty a reserved source before it regenerates.
+const MAX_REMOTE_WORK: u8 = 6;
+
+/// Most WORK parts a remote harvester built for `max_energy` can have, with a
+/// CARRY for building its container and a MOVE for every two WORK.
+pub fn remote_harvester_work(max_energy: u32) -> u8 {
+  (1..=MAX_REMOTE_WORK)
+    .rev()
+    .find(|work| remote_harvester_design(*work).base_cost() <= max_energy)
+    .unwrap_or(1)
+}
+
+pub fn remote_harvester_design(work_parts: u8) -> BodyDesign {
+  BodyDesign::new()
+    .work(work_parts)
+    .carry(1)
+    .r#move(work_parts.div_ceil(2))
+}
+
+/// Harvests a source in another room into the container next to it, and
+/// builds and repairs that container.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct RemoteHarvester {
+  #[persist(0, "cbor::object_id")]
+  pub source: ObjectId<Source>,
+  #[persist(1, "cbor::position")]
+  pub source_pos: Position,
+  #[persist(2)] pub work_parts: u8,
+  #[serde(default)]
+  pub travel: Travel,
+}
+
+impl RemoteHarvester {
+  pub fn new(op: &RemoteOperation, max_energy: u32) -> Self {
+    RemoteHarvester {
+      source: op.source,
+      source_pos: op.source_pos,
+      work_parts: remote_harvester_work(max_energy),
+      travel: Travel::default(),
+    }
+  }
+
+  /// Spend what it's carrying on building or repairing the container.
+  /// Returns false if there's nothing to do, so it keeps harvesting and
+  /// the overflow drops into the container it stands on.
+  fn maintain(&self, creep: &Creep, source: &Source) -> bool {
+    let site = creep.pos().find_in_range(find::MY_CONSTRUCTION_SITES, 1)
+      .into_iter()
+      .find(|site| site.structure_type() == StructureType::Container);
+    if let Some(site) = site {
+      log_warn!(creep.build(&site),
+                err => "Remote harvester {} could not build: {err:?}", creep.id_str());
+      return true
+    }
+    let Some(container) = source_container(source) else {
+      // start one where we stand, next to the source.
+      log_warn!(creep.pos().create_construction_site(StructureType::Container, None),
+                err => "Remote harvester {} could not place a container: {err:?}",
+                creep.id_str());
+      return true
+    };
+    if repair::needs_repair(container.hits(), container.hits_max()) {
+      log_warn!(creep.repair(&container),
+                err => "Remote harvester {} could not repair: {err:?}", creep.id_str());
+      return true
+    }
+    false
+  }
+}
+
+impl Role for RemoteHarvester {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    if !travel::travel_to(creep, &mut self.travel, self.source_pos, 1) {
+      return
+    }
+    let Some(source) = self.source.resolve() else {
+      return
+    };
+    if let Some(container) = source_container(&source) {
+      if creep.pos() != container.pos() {
+        move_to_do(creep, &container, 0, || ());
+        return
+      }
+    }
+    if energy_full(creep) && self.maintain(creep, &source) {
+      return
+    }
+    match creep.harvest(&source) {
+      Ok(()) | Err(ErrorCode::NotEnough) => (),
+      Err(err) => warn!("Remote harvester {} could not harvest: {err:?}", creep.id_str()),
+    }
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub enum RemoteHaulerState {
+  #[persist(0)] Collecting,
+  #[persist(1)] Delivering,
+}
+
+/// Carries energy from a remote source's container back home.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct RemoteHauler {
+  #[persist(0)] pub state: RemoteHaulerState,
+  #[persist(1, "cbor::object_id")]
+  pub source: ObjectId<Source>,
+  #[persist(2, "cbor::position")]
+  pub source_pos: Position,
+  #[persist(3, "cbor::room_name")]
+  pub home: RoomName,
+  #[serde(default)]
+  pub travel: Travel,
+}
+
+impl RemoteHauler {
+  pub fn new(op: &RemoteOperation, home: RoomName) -> Self {
+    RemoteHauler {
+      state: RemoteHaulerState::Collecting,
+      source: op.source,
+      source_pos: op.source_pos,
+      home,
+      travel: Travel::default(),
+    }
+  }
+
+  fn collect(&mut self, creep: &Creep) {
+    if !travel::travel_to(creep, &mut self.travel, self.source_pos, 2) {
+      return
+    }
+    // pick up what spilled first, before it decays.
+    let dropped = self.source_pos.find_in_range(find::DROPPED_RESOURCES, 2)
+      .into_iter()
+      .find(|resource| resource.resource_type() == ResourceType::Energy);
+    if let Some(dropped) = dropped {
+      move_to_do(creep, &dropped, 1, || {
+        log_warn!(creep.pickup(&dropped),
+                  err => "Remote hauler {} could not pick up: {err:?}", creep.id_str());
+      });
+      return
+    }
+    let Some(container) = self.source.resolve().and_then(|s| source_container(&s)) else {
+      return
+    };
+    move_to_do(creep, &container, 1, || {
+      match creep.withdraw(&container, ResourceType::Energy, None) {
+        Ok(()) | Err(ErrorCode::NotEnough) => (),
+        Err(err) => warn!("Remote hauler {} could not withdraw: {err:?}", creep.id_str()),
+      }
+    });
+  }
+
+  fn deliver(&mut self, creep: &Creep) {
+    if creep.pos().room_name() != self.home {
+      let Some(drop_off) = game::rooms().get(self.home)
+        .and_then(|home| hauler::drop_off_pos(&home)) else {
+        return
+      };
+      travel::travel_to(creep, &mut self.travel, drop_off, 1);
+      return
+    }
+    let Some(sink) = hauler::find_energy_sink(creep) else {
+      return
+    };
+    move_to_do(creep, &sink, 1, || {
+      log_warn!(creep.transfer(&sink, ResourceType::Energy, None),
+                err => "Remote hauler {} could not deliver energy: {err:?}", creep.id_str());
+    });
+  }
+}
+
+impl Role for RemoteHauler {
+  fn run(&mut self, creep: &Creep, _memory: &mut Memory) {
+    use RemoteHaulerState::*;
+    match self.state {
+      Collecting if energy_full(creep) => self.state = Delivering,
+      Delivering if energy_empty(creep) => self.state = Collecting,
+      _ => (),
+    }
+    match self.state {
+      Collecting => self.collect(creep),
+      Delivering => self.deliver(creep),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn remote_harvesters_fit_the_energy() {
+    // WORK WORK CARRY MOVE.
+    assert_eq!(remote_harvester_work(300), 2);
+    assert_eq!(remote_harvester_work(550), 4);
+    assert_eq!(remote_harvester_work(10_000), MAX_REMOTE_WORK);
+    assert_eq!(remote_harvester_design(5).size(), 9);
+    for budget in [300, 550, 800] {
+      assert!(remote_harvester_design(remote_harvester_work(budget)).fits(budget));
+    }
+  }
+}
@@ -0,0 +1,115 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::{prelude::*, Creep, ErrorCode, Part, ResourceType, Structure};
+
+use super::role::Role;
+use super::worker::get_nearest_energy_supplier;
+use crate::body::BodyDesign;
+use crate::managers::repair;
+use crate::memory::Memory;
+use crate::storage::cbor;
+use crate::util::{energy_empty, energy_full, move_to_do, PrettyId};
+use crate::log_warn;
+
+/// Most WORK CARRY MOVE segments in a repairer. Decay is slow, so a small
+/// one keeps up.
+const MAX_SEGMENTS: u32 = 5;
+
+/// The number of WORK CARRY MOVE segments we can afford for `max_energy`.
+pub fn repairer_segments(max_energy: u32) -> u8 {
+  let segment = Part::Work.cost() + Part::Carry.cost() + Part::Move.cost();
+  (max_energy / segment).clamp(1, MAX_SEGMENTS) as u8
+}
+
+pub fn repairer_design(work_parts: u8) -> BodyDesign {
+  BodyDesign::new()
+    .work(work_parts)
+    .carry(work_parts)
+    .r#move(work_parts)
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub enum RepairerState {
+  #[persist(0)] Idle,
+  #[persist(1)] Refueling(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<Structure>),
+  #[persist(2)] Repairing(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<Structure>),
+}
+
+/// Repairs the structures the repair queue gives it up to their hit target.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Repairer {
+  #[persist(0)] pub state: RepairerState,
+  /// How many WORK parts it was spawned with.
+  #[persist(1)] pub work_parts: u8,
+}
+
+impl Repairer {
+  pub fn new(work_parts: u8) -> Self {
+    Repairer { state: RepairerState::Idle, work_parts }
+  }
+
+  fn find_repair(&mut self, creep: &Creep) {
+    let target = creep.room().and_then(|room| repair::next_repair(&room));
+    self.state = match target.map(|structure| structure.id()) {
+      Some(id) if energy_empty(creep) => RepairerState::Refueling(id),
+      Some(id) => RepairerState::Repairing(id),
+      None => RepairerState::Idle,
+    };
+  }
+}
+
+impl Role for Repairer {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    use RepairerState::*;
+    match &self.state {
+      Idle => self.find_repair(creep),
+      Refueling(target) if energy_full(creep) => self.state = Repairing(*target),
+      Repairing(target) if energy_empty(creep) => self.state = Refueling(*target),
+      _ => (),
+    }
+
+    match &self.state {
+      Idle => (),
+      Refueling(_) => {
+        let Some(room) = creep.room() else {
+          return
+        };
+        let Some(supplier) = get_nearest_energy_supplier(&room, creep.pos()) else {
+          return
+        };
+        move_to_do(creep, &supplier, 1, || {
+          match creep.withdraw(&supplier, ResourceType::Energy, None) {
+            Ok(()) | Err(ErrorCode::NotEnough) => (),
+            Err(err) => warn!("Repairer {} could not withdraw energy: {err:?}", creep.id_str()),
+          }
+        });
+      }
+      Repairing(id) => {
+        let target = id.resolve()
+          .filter(|structure| structure.hits() < repair::target_for(structure));
+        let Some(target) = target else {
+          // it's done, or gone.
+          self.find_repair(creep);
+          if matches!(self.state, Repairing(_)) {
+            self.run(creep, memory);
+          }
+          return
+        };
+        move_to_do(creep, &target, 3, || {
+          log_warn!(creep.repair(&target),
+                    err => "Repairer {} could not repair: {err:?}", creep.id_str());
+        });
+      }
+    }
+  }
+
+  fn is_idle(&self) -> bool {
+    matches!(self.state, RepairerState::Idle)
+  }
+}
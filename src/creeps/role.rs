@@ -18,6 +18,15 @@ pub trait Role: Clone {
   /// To avoid borrowing the creep's memory twice, we just clone the self at
   /// the start and then assign it back at the end.
   fn run(&mut self, creep: &Creep, memory: &mut Memory);
+
+  /// Called once the creep has died and its memory has been removed, to
+  /// release any source, target or reservation it was holding.
+  fn on_death(&self, name: &str, memory: &mut Memory) {}
+
+  /// Whether the creep found nothing to do this tick.
+  fn is_idle(&self) -> bool {
+    false
+  }
 }
 
 /*
@@ -0,0 +1,71 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::{prelude::*, game, Creep, Room, RoomName};
+
+use super::role::Role;
+use crate::body::BodyDesign;
+use crate::creeps::CreepMemory;
+use crate::memory::Memory;
+use crate::memory::intel::{stalest, STALE_AFTER};
+use crate::storage::cbor;
+use crate::util::travel::{self, Travel};
+
+/// How many rooms away from home a scout will go.
+const SCOUT_RANGE: u32 = 5;
+
+pub fn scout_design() -> BodyDesign {
+  BodyDesign::new().r#move(1)
+}
+
+/// When each room next to `room` was last seen, `None` if never.
+fn neighbors_last_seen(room: RoomName, memory: &Memory) -> Vec<(RoomName, Option<u32>)> {
+  game::map::describe_exits(room)
+    .values()
+    .map(|name| (name, memory.intel(name).map(|intel| intel.last_seen)))
+    .collect()
+}
+
+/// A scout for `room` if it hasn't got one and any room next to it is
+/// unexplored or stale.
+pub fn next_scout(room: &Room, memory: &Memory) -> Option<Scout> {
+  let home = room.name();
+  let has_scout = memory.creeps.values()
+    .any(|mem| matches!(mem, CreepMemory::Scout(scout) if scout.home == home));
+  if has_scout {
+    return None
+  }
+  let time = game::time();
+  let stale = neighbors_last_seen(home, memory)
+    .into_iter()
+    .filter(|(_, seen)| seen.map_or(true, |seen| seen + STALE_AFTER <= time));
+  stalest(stale).map(|target| Scout { home, target, travel: Travel::default() })
+}
+
+/// Walks through the rooms around its home that we know the least about, so
+/// the intel on them gets recorded.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Scout {
+  #[persist(0, "cbor::room_name")]
+  pub home: RoomName,
+  #[persist(1, "cbor::room_name")]
+  pub target: RoomName,
+  #[serde(default)]
+  pub travel: Travel,
+}
+
+impl Role for Scout {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    if creep.pos().room_name() == self.target {
+      let home = self.home;
+      let next = neighbors_last_seen(self.target, memory)
+        .into_iter()
+        .filter(|(name, _)| game::map::get_room_linear_distance(home, *name, false) <= SCOUT_RANGE);
+      if let Some(next) = stalest(next) {
+        debug!("scout heading from {} to {next}", self.target);
+        self.target = next;
+      }
+    }
+    travel::travel_to(creep, &mut self.travel, travel::room_center(self.target), 20);
+  }
+}
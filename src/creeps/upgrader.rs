@@ -0,0 +1,181 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use log::*;
+use screeps::local::ObjectId;
+use screeps::{prelude::*, find, Creep, Room, RoomName, StructureObject, ResourceType, Part};
+use screeps::constants::{
+  CONTROLLER_MAX_UPGRADE_PER_TICK, CREEP_LIFE_TIME, ENERGY_REGEN_TIME, MAX_CREEP_SIZE,
+};
+
+use super::role::Role;
+use super::worker::Supplier;
+use crate::body::BodyDesign;
+use crate::creeps::CreepMemory;
+use crate::managers::city;
+use crate::memory::Memory;
+use crate::util::{energy_empty, move_to_do, PrettyId};
+use crate::{log_warn, mk_cache};
+
+/// Energy per tick it costs to keep respawning one WORK part and the half a
+/// MOVE part that carries it.
+const UPKEEP_PER_WORK: f64 = (100.0 + 25.0) / CREEP_LIFE_TIME as f64;
+
+/// How many WORK parts of upgrading a room can afford with `surplus` energy
+/// per tick left over.
+pub fn upgrade_work_budget(surplus: f64, level: u8) -> u32 {
+  let budget = (surplus / (1.0 + UPKEEP_PER_WORK)).floor().max(0.0) as u32;
+  if level >= 8 {
+    budget.min(CONTROLLER_MAX_UPGRADE_PER_TICK)
+  } else {
+    budget
+  }
+}
+
+/// The most WORK parts an upgrader built for `max_cost` can have, with one
+/// CARRY and a MOVE for every two WORK.
+fn max_work_for_cost(max_cost: u32) -> u32 {
+  let available = max_cost.saturating_sub(Part::Carry.cost());
+  let pairs = available / (2 * Part::Work.cost() + Part::Move.cost());
+  let left_over = available % (2 * Part::Work.cost() + Part::Move.cost());
+  let work = 2 * pairs + u32::from(left_over >= Part::Work.cost() + Part::Move.cost());
+  // WORK + MOVE + CARRY has to fit in a creep.
+  let max_size = 2 * (MAX_CREEP_SIZE - 1) / 3;
+  work.clamp(1, max_size)
+}
+
+/// The WORK parts for the next upgrader, if the ones we have don't use up the
+/// budget.
+pub fn next_upgrader_work(budget: u32, existing: u32, max_cost: u32) -> Option<u32> {
+  if existing >= budget {
+    None
+  } else {
+    Some((budget - existing).min(max_work_for_cost(max_cost)))
+  }
+}
+
+pub fn upgrader_design(work_parts: u8) -> BodyDesign {
+  BodyDesign::new()
+    .work(work_parts)
+    .carry(1)
+    .r#move((work_parts + 1) / 2)
+}
+
+mk_cache! {
+  controller_supplier_cache lifetime 100 by RoomName => Option<ObjectId<Supplier>>
+}
+
+/// The link or container next to the controller that upgraders pull from.
+///
+/// Only looks within range 2, so everything next to it is in upgrade range.
+pub fn controller_supplier(room: &Room) -> Option<Supplier> {
+  let controller = room.controller()?;
+  controller_supplier_cache::caches_tagged(&room.name(), &[room.name().into()], |_| {
+    let mut structures = controller.pos().find_in_range(find::STRUCTURES, 2);
+    // prefer a link since nothing has to haul to it.
+    structures.sort_by_key(|s| !matches!(s, StructureObject::StructureLink(_)));
+    structures.into_iter()
+      .find_map(|structure| match structure {
+        StructureObject::StructureLink(link) if link.my() => Some(Supplier::from(link)),
+        StructureObject::StructureContainer(cont) => Some(Supplier::from(cont)),
+        _ => None,
+      })
+      .map(|supplier| supplier.id())
+  }).and_then(|id| id.resolve())
+}
+
+mk_cache! {
+  room_energy_surplus lifetime 50 by RoomName => f64
+}
+
+/// Energy per tick the sources in the room make, minus what it costs to keep
+/// respawning every creep in it except the upgraders.
+fn energy_surplus(room: &Room, memory: &Memory) -> f64 {
+  room_energy_surplus::caches_tagged(&room.name(), &[room.name().into()], |_| {
+    let income: f64 = room.find(find::SOURCES, None)
+      .iter()
+      .map(|source| source.energy_capacity() as f64 / ENERGY_REGEN_TIME as f64)
+      .sum();
+    let upkeep: f64 = room.find(find::MY_CREEPS, None)
+      .iter()
+      .filter(|creep| !matches!(memory.creep(creep), Some(CreepMemory::Upgrader(_))))
+      .map(|creep| creep.body().iter().map(|part| part.part().cost()).sum::<u32>() as f64)
+      .sum::<f64>() / CREEP_LIFE_TIME as f64;
+    income - upkeep
+  })
+}
+
+/// The next upgrader to spawn in the room, if there's surplus energy that
+/// isn't being spent on upgrading already.
+pub fn next_upgrader(room: &Room, memory: &Memory) -> Option<Upgrader> {
+  let level = room.controller()?.level();
+  let budget = upgrade_work_budget(energy_surplus(room, memory), level);
+  let existing: u32 = room.find(find::MY_CREEPS, None)
+    .iter()
+    .filter(|creep| matches!(memory.creep(creep), Some(CreepMemory::Upgrader(_))))
+    .map(|creep| creep.get_active_bodyparts(Part::Work) as u32)
+    .sum();
+  let work = next_upgrader_work(budget, existing, city::spawn_budget(room))?;
+  Some(Upgrader { work_parts: work as u8 })
+}
+
+/// Parks next to the controller's link or container and upgrades.
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Upgrader {
+  /// How many WORK parts it was spawned with.
+  #[persist(0)] pub work_parts: u8,
+}
+
+impl Role for Upgrader {
+  fn run(&mut self, creep: &Creep, memory: &mut Memory) {
+    let Some(room) = creep.room() else {
+      return
+    };
+    let Some(controller) = room.controller() else {
+      warn!("Upgrader {} in room without controller", creep.id_str());
+      return
+    };
+    let upgrade = || if !energy_empty(creep) {
+      log_warn!(creep.upgrade_controller(&controller),
+                err => "Upgrader {} could not upgrade controller: {err:?}", creep.id_str());
+    };
+    let Some(supplier) = controller_supplier(&room) else {
+      move_to_do(creep, &controller, 3, upgrade);
+      return
+    };
+    move_to_do(creep, &supplier, 1, || {
+      // top up before we run out so we never miss a tick of upgrading.
+      let energy = creep.store().get_used_capacity(Some(ResourceType::Energy));
+      if energy <= 2 * creep.get_active_bodyparts(Part::Work) as u32
+        && !energy_empty(&supplier) {
+        log_warn!(creep.withdraw(&supplier, ResourceType::Energy, None),
+                  err => "Upgrader {} could not withdraw energy: {err:?}", creep.id_str());
+      }
+      upgrade();
+    });
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn upgraders_scale_with_surplus() {
+    assert_eq!(upgrade_work_budget(-3.0, 4), 0);
+    assert_eq!(upgrade_work_budget(10.0, 4), 9);
+    // capped at RCL 8.
+    assert_eq!(upgrade_work_budget(40.0, 8), CONTROLLER_MAX_UPGRADE_PER_TICK);
+
+    // 550 energy: CARRY, then two lots of WORK WORK MOVE.
+    assert_eq!(max_work_for_cost(550), 4);
+    assert_eq!(max_work_for_cost(400), 2);
+    assert_eq!(next_upgrader_work(9, 4, 550), Some(4));
+    assert_eq!(next_upgrader_work(9, 8, 550), Some(1));
+    assert_eq!(next_upgrader_work(9, 9, 550), None);
+    let design = upgrader_design(max_work_for_cost(100_000) as u8);
+    assert!(design.size() as u32 <= MAX_CREEP_SIZE);
+    for budget in [300, 550, 1300] {
+      assert!(upgrader_design(max_work_for_cost(budget) as u8).fits(budget));
+    }
+  }
+}
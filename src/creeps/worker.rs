@@ -1,4 +1,5 @@
-use minicbor::{Encode, Decode};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use log::*;
 use screeps::pathfinder::SingleRoomCostResult;
 use std::assert_matches::assert_matches;
@@ -8,12 +9,14 @@ use screeps::{prelude::*, RawObjectId};
 use screeps::{
   find, TransferableObject, Creep, Source, StructureController, ConstructionSite,
   StructureSpawn, StoreObject, Position, StructureContainer, StructureLink,
+  StructureStorage, StructureTerminal,
   Structure, StructureType, RoomObject, Store, pathfinder, Room, Path,
 };
 use screeps::traits::{Resolvable, HasTypedId};
 use screeps::constants::{ResourceType, ErrorCode};
 use wasm_bindgen::prelude::*;
 
+use crate::managers::construction;
 use crate::storage::cbor;
 use crate::memory::Memory;
 use crate::util::{path_len, energy_full, energy_empty, move_to_do};
@@ -23,73 +26,90 @@ use crate::log_warn;
 
 #[wasm_bindgen]
 extern "C" {
-  /// Object representing something that a worker can get energy from.
+  /// Object representing something a creep can withdraw resources from.
   ///
-  /// Currently should only be a container or link.
+  /// Currently should only be a container, link, storage or terminal. Links
+  /// only ever hold energy.
   #[wasm_bindgen(extends = RoomObject, extends = Structure)]
   #[derive(Clone, Debug)]
-  pub type EnergySupplier;
+  pub type Supplier;
 
-  /// The [`Store`] of the extension, which contains information about the
-  /// amount of energy in it.
+  /// The [`Store`] of the structure, which contains information about the
+  /// resources in it.
   ///
   /// [Screeps documentation](https://docs.screeps.com/api/#StructureLink.store)
   #[wasm_bindgen(method, getter)]
-  pub fn store(this: &EnergySupplier) -> Store;
+  pub fn store(this: &Supplier) -> Store;
 }
 
-impl Transferable for EnergySupplier {}
-impl Withdrawable for EnergySupplier {}
+impl Transferable for Supplier {}
+impl Withdrawable for Supplier {}
 
-impl HasStore for EnergySupplier {
+impl HasStore for Supplier {
   fn store(&self) -> Store {
     Self::store(self)
   }
 }
 
-impl From<StructureContainer> for EnergySupplier {
+impl From<StructureContainer> for Supplier {
   fn from(value: StructureContainer) -> Self {
     JsValue::from(value).into()
   }
 }
 
-impl From<StructureLink> for EnergySupplier {
+impl From<StructureLink> for Supplier {
   fn from(value: StructureLink) -> Self {
     JsValue::from(value).into()
   }
 }
 
-#[derive(Clone, PartialEq, Debug, Encode, Decode)]
+impl From<StructureStorage> for Supplier {
+  fn from(value: StructureStorage) -> Self {
+    JsValue::from(value).into()
+  }
+}
+
+impl From<StructureTerminal> for Supplier {
+  fn from(value: StructureTerminal) -> Self {
+    JsValue::from(value).into()
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub enum Worker {
-  #[n(0)] Idle,
-  #[n(1)] Transfer(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(0)] Idle,
+  #[persist(1)] Transfer(
+    #[persist(0, "cbor::object_id")]
     ObjectId<EnergySink>),
-  #[n(2)] Upgrade(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(2)] Upgrade(
+    #[persist(0, "cbor::object_id")]
     ObjectId<StructureController>),
-  #[n(3)] Build(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
+  #[persist(3)] Build(
+    #[persist(0, "cbor::object_id")]
     ObjectId<ConstructionSite>),
-  #[n(4)] TakeFrom(
-    #[n(0)] #[cbor(with = "cbor::object_id")]
-    ObjectId<EnergySupplier>),
+  #[persist(4)] TakeFrom(
+    #[persist(0, "cbor::object_id")]
+    ObjectId<Supplier>),
 }
 
 /// Get which store the place should travel to to resupply on energy.
-fn get_nearest_energy_supplier(room: &Room, pos: Position) -> Option<EnergySupplier> {
+///
+/// Containers aren't owned, so this looks through every structure rather
+/// than just ours.
+pub fn get_nearest_energy_supplier(room: &Room, pos: Position) -> Option<Supplier> {
   use StructureObject::*;
   use pathfinder::SingleRoomCostResult;
   use screeps::{CostMatrix, RoomName};
-  room.find(find::MY_STRUCTURES, None)
+  room.find(find::STRUCTURES, None)
     .into_iter()
     .filter_map(|structure| match structure {
-      StructureContainer(cont) => Some(EnergySupplier::from(cont)),
-      StructureLink(link) => Some(EnergySupplier::from(link)),
+      StructureContainer(cont) => Some(Supplier::from(cont)),
+      StructureLink(link) if link.my() => Some(Supplier::from(link)),
       _ => None,
     })
+    .filter(|supplier| !energy_empty(supplier))
     .min_by_key(|supplier| path_len(&pos.find_path_to
-                                    ::<EnergySupplier,
+                                    ::<Supplier,
                                        fn(RoomName, CostMatrix) -> SingleRoomCostResult,
                                        SingleRoomCostResult
                                        >(supplier, None)))
@@ -124,9 +144,13 @@ impl Worker {
         *self = Transfer(EnergySink::from(spawn.clone()).id());
       }
       _ => {
-        let site_id = creep.pos()
-          .find_closest_by_range(find::MY_CONSTRUCTION_SITES)
-          .and_then(|site| site.try_id());
+        // builders are assigned sites so they don't all pile onto one, so
+        // leave building to them once there are any.
+        let site_id = if construction::has_builders(&room, memory) {
+          None
+        } else {
+          construction::top_site(&room)
+        };
 
         *self = match site_id {
           Some(site) => Build(site),
@@ -144,6 +168,10 @@ impl Worker {
 }
 
 impl Role for Worker {
+  fn is_idle(&self) -> bool {
+    matches!(self, Worker::Idle)
+  }
+
   fn run(&mut self, creep: &Creep, memory: &mut Memory) {
     use Worker::*;
     match self {
@@ -185,7 +213,7 @@ impl Role for Worker {
           self.run(creep, memory);
           return
         };
-        move_to_do(creep, &controller, 1, || {
+        move_to_do(creep, &controller, 3, || {
           log_warn!(creep.upgrade_controller(&controller),
                     err => "Creep could not upgrade controller: {err:?}");
         });
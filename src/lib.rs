@@ -13,12 +13,13 @@ mod logging;
 mod memory;
 mod creeps;
 mod managers;
+mod console;
 
 use std::cell::RefCell;
 use std::collections::hash_map::Entry;
 use std::collections::{HashMap, HashSet};
 
-use js_sys::{JsString, Object, Reflect};
+use js_sys::JsString;
 use log::*;
 use rooms::tile_min_cut::{build_cost_matrix, min_cut_to_exit};
 use screeps::constants::{ErrorCode, Part, ResourceType};
@@ -28,7 +29,6 @@ use screeps::objects::{Creep, Source, StructureController, Room, StructureSpawn}
 use screeps::{prelude::*, RoomXY};
 use screeps::{find, game, RoomName};
 use wasm_bindgen::prelude::*;
-use wasm_bindgen::JsCast;
 
 use crate::memory::Memory;
 use crate::storage::serialization::with_memory;
@@ -158,44 +158,13 @@ pub fn game_loop() {
   });
   with_memory(|mem| {
     //info!("count: {}", mem.creep_counter);
+    memory::collect_garbage(mem);
+    memory::intel::record_visible_rooms(mem);
+    managers::remote::remote_loop(mem);
     managers::city::spawn_loop(mem);
+    managers::repair::repair_loop();
     creeps::creep_loop::creep_loop(mem);
-    clean_up(mem);
 
   });
   info!("done! cpu: {}", game::cpu::get_used());
 }
-
-mod my_game {
-  use wasm_bindgen::prelude::*;
-  #[wasm_bindgen]
-  extern "C" {
-    pub type Game;
-
-    #[wasm_bindgen(static_method_of = Game, getter = creeps)]
-    pub fn creeps() -> JsValue;
-  }
-}
-
-fn clean_up(memory: &mut Memory) {
-  if game::time() % 1000 == 0 {
-    debug!("running memory cleanup");
-    let game_creeps = my_game::Game::creeps();
-    if let Ok(memory_creeps) = Reflect::get(&screeps::memory::ROOT, &JsString::from("creeps")) {
-      // convert from JsValue to Object
-      let memory_creeps: Object = memory_creeps.unchecked_into();
-      // iterate memory creeps
-      for creep_name_js in Object::keys(&memory_creeps).iter() {
-        // convert to String (after converting to JsString)
-        let creep_name = String::from(creep_name_js.dyn_ref::<JsString>().unwrap());
-
-        // check the HashSet for the creep name, deleting if not alive
-        if Reflect::has(&game_creeps, &creep_name_js).unwrap() {
-          info!("deleting memory for dead creep {}", creep_name);
-          let _ = Reflect::delete_property(&memory_creeps, &creep_name_js);
-          let _ = memory.creeps.remove(&creep_name);
-        }
-      }
-    }
-  }
-}
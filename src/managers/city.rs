@@ -8,14 +8,21 @@ use screeps::{
   RoomName, find, prelude::*, Room, ResourceType, StructureType, RoomXY, Position,
   look, Terrain, game, RoomCoordinate, FindPathOptions, Part, StructureObject, Resource,
 };
+use screeps::constants::MAX_CREEP_SIZE;
 use crate::creeps::early_worker::EarlyWorker;
 use crate::util::{self, look_at_square, PrettyId};
 use crate::body::BodyDesign;
 use crate::creeps::CreepMemory;
-use crate::memory::Memory;
+use crate::memory::{Memory, SpawnMemory};
+use crate::storage::cache;
+use crate::storage::serialization::request_save;
 use crate::{mk_cache, log_warn};
 use crate::creeps::RoleTag;
 use crate::creeps::harvester::{self, lair_for_source};
+use crate::creeps::{
+  builder, claimer, defender, hauler, mineral, remote, repairer, scout, upgrader,
+};
+use crate::managers::{self, colony, construction, defense, repair};
 use crate::creeps::worker;
 
 fn edges_around(xy: &RoomXY, radius: i8) -> impl Iterator<Item = RoomXY> {
@@ -62,9 +69,89 @@ fn place_storage_container_for(source: &Source) {
   if let Err(e) = pos.create_construction_site(StructureType::Container, None) {
     warn!("Error creating construction site {} {}: {e:?}", pos.x(), pos.y());
   }
+  construction::sites_changed(&room);
   harvester::have_updated_source_storage(source);
 }
 
+/// Put a container for upgraders two squares from the controller, unless
+/// there is already one built or being built.
+fn place_controller_container(room: &Room) {
+  use screeps::pathfinder::{SearchGoal, search_many};
+  let Some(controller) = room.controller() else {
+    return
+  };
+  let controller_pos = controller.pos();
+  let built = controller_pos.find_in_range(find::STRUCTURES, 2)
+    .iter()
+    .any(|s| matches!(s, StructureObject::StructureContainer(_)));
+  if built {
+    // it finished since we last looked.
+    cache::invalidate_tag(room.name());
+    return
+  }
+  let building = controller_pos.find_in_range(find::MY_CONSTRUCTION_SITES, 2)
+    .iter()
+    .any(|site| site.structure_type() == StructureType::Container);
+  if building {
+    return
+  }
+  let goals = open_edges_around(room, &controller_pos.xy(), 2)
+    .map(|xy| SearchGoal::new(Position::new(xy.x, xy.y, room.name()), 0));
+  let search_result = search_many(controller_pos, goals, Some(util::local_search_opts()));
+  let Some(pos) = search_result.path().pop() else {
+    warn!("No open spot for a controller container in {}", room.name());
+    return
+  };
+  if let Err(e) = pos.create_construction_site(StructureType::Container, None) {
+    warn!("Error creating construction site {} {}: {e:?}", pos.x(), pos.y());
+  }
+  construction::sites_changed(room);
+  cache::invalidate_tag(room.name());
+}
+
+/// Controller level that unlocks the extractor.
+const EXTRACTOR_LEVEL: u8 = 6;
+/// How often a room checks its mineral has an extractor and container.
+const MINERAL_PLAN_INTERVAL: u32 = 100;
+
+/// Put an extractor on the room's mineral and a container next to it, on the
+/// side closest to where haulers drop off, once the controller allows it.
+fn place_mineral_structures(room: &Room) {
+  use screeps::pathfinder::{SearchGoal, search_many};
+  if room.controller().map_or(true, |c| c.level() < EXTRACTOR_LEVEL) {
+    return
+  }
+  for mineral in room.find(find::MINERALS, None) {
+    let mineral_pos = mineral.pos();
+    let extractor_site = mineral_pos.find_in_range(find::MY_CONSTRUCTION_SITES, 0)
+      .iter()
+      .any(|site| site.structure_type() == StructureType::Extractor);
+    if mineral::extractor(&mineral).is_none() && !extractor_site {
+      log_warn!(mineral_pos.create_construction_site(StructureType::Extractor, None), err =>
+                "Error creating extractor site in {}: {err:?}", room.name());
+      construction::sites_changed(room);
+    }
+    if harvester::nearby_mineral_storage(&mineral).is_some() {
+      continue
+    }
+    let terrain = room.get_terrain();
+    let goals = util::xy::surrounding_xy(mineral_pos.xy())
+      .filter(|xy| terrain.get(xy.x.into(), xy.y.into()) != Terrain::Wall)
+      .map(|xy| SearchGoal::new(Position::new(xy.x, xy.y, room.name()), 0));
+    let from = hauler::drop_off_pos(room).unwrap_or(mineral_pos);
+    let search_result = search_many(from, goals, Some(util::local_search_opts()));
+    let Some(pos) = search_result.path().pop() else {
+      warn!("No open spot for a mineral container in {}", room.name());
+      continue
+    };
+    if let Err(e) = pos.create_construction_site(StructureType::Container, None) {
+      warn!("Error creating construction site {} {}: {e:?}", pos.x(), pos.y());
+    }
+    construction::sites_changed(room);
+    harvester::have_updated_mineral_storage(&mineral);
+  }
+}
+
 fn building_locations(room: &Room) -> impl Iterator<Item = Position> + '_ {
   const RADIUS_AROUND_SPAWN: i8 = 5;
   const INNER_RADIUS: u32 = 3;
@@ -113,6 +200,7 @@ pub fn place_spawn_extension(room: &Room) {
         match pos.create_construction_site(StructureType::Extension, None) {
           Ok(()) => {
             HAS_ADDED_SPAWN_EXT_THIS_TICK.set(true);
+            construction::sites_changed(room);
           }
           Err(err) => {
             warn!("Failed to make spawn extension in {} because {err:?}", room.name());
@@ -144,7 +232,8 @@ mk_cache! {
 }
 
 pub fn current_role_count(room: &Room, memory: &Memory, role: RoleTag) -> u32 {
-  cache_current_role_count::caches(&(room.name(), role), |_| {
+  let tags = [room.name().into()];
+  cache_current_role_count::caches_tagged(&(room.name(), role), &tags, |_| {
     room.find(find::MY_CREEPS, None)
       .into_iter()
       .filter(|creep| memory
@@ -168,7 +257,8 @@ fn design_for_memory(memory: &CreepMemory, max_energy: u32) -> BodyDesign {
       let design = BodyDesign::new().r#move(1).carry(1);
       let base_cost = design.base_cost();
       let available = max_energy - base_cost;
-      let work_num = available / Part::Work.cost();
+      let work_num = (available / Part::Work.cost())
+        .min(MAX_CREEP_SIZE - design.size() as u32);
       design.work(work_num.try_into().unwrap())
     }
     CreepMemory::Worker(_) =>
@@ -181,6 +271,20 @@ fn design_for_memory(memory: &CreepMemory, max_energy: u32) -> BodyDesign {
       .r#move(1)
       .carry(2)
       .work(1),
+    CreepMemory::Hauler(_) => hauler::hauler_design(max_energy),
+    CreepMemory::Upgrader(mem) => upgrader::upgrader_design(mem.work_parts),
+    CreepMemory::Builder(mem) => builder::builder_design(mem.work_parts),
+    CreepMemory::Repairer(mem) => repairer::repairer_design(mem.work_parts),
+    CreepMemory::Defender(mem) => defender::defender_design(mem.segments),
+    CreepMemory::RangedDefender(mem) => defender::ranged_defender_design(mem.segments),
+    CreepMemory::Healer(mem) => defender::healer_design(mem.segments),
+    CreepMemory::Claimer(_) => claimer::claimer_design(),
+    CreepMemory::Reserver(mem) => claimer::reserver_design(mem.segments),
+    CreepMemory::RemoteHarvester(mem) => remote::remote_harvester_design(mem.work_parts),
+    CreepMemory::RemoteHauler(_) => hauler::hauler_design(max_energy),
+    CreepMemory::Scout(_) => scout::scout_design(),
+    CreepMemory::MineralMiner(mem) => mineral::mineral_miner_design(mem.work_parts),
+    CreepMemory::MineralHauler(_) => mineral::mineral_hauler_design(max_energy),
   }
 }
 
@@ -218,7 +322,7 @@ mk_cache! {
 /// TODO: I think we should have it exit early city before RCL 3, since that's so
 /// expensive.
 fn is_city_early(room: &Room, memory: &Memory) -> bool {
-  city_early_cache::caches(&room.name(), |_| {
+  city_early_cache::caches_tagged(&room.name(), &[room.name().into()], |_| {
     let Some(controller) = room.controller() else {
       warn!("Running city code on room without controller {}", room.name());
       return true;
@@ -233,10 +337,30 @@ fn is_city_early(room: &Room, memory: &Memory) -> bool {
   })
 }
 
+fn defender_for(role: RoleTag, max_energy: u32) -> Option<CreepMemory> {
+  use defender::*;
+  match role {
+    RoleTag::Defender =>
+      Some(Defender { segments: defender_segments(max_energy), idle: false }.into()),
+    RoleTag::RangedDefender =>
+      Some(RangedDefender { segments: ranged_defender_segments(max_energy), idle: false }.into()),
+    RoleTag::Healer =>
+      Some(Healer { segments: healer_segments(max_energy), idle: false }.into()),
+    _ => None,
+  }
+}
+
+/// Most builders a room will spawn at once.
+const MAX_BUILDERS: u32 = 3;
+/// Most repairers a room will spawn at once.
+const MAX_REPAIRERS: u32 = 1;
+
 fn pick_next_creep(room: &Room, memory: &Memory) -> Option<CreepMemory> {
   use std::cmp::max;
 
-  if is_city_early(room, memory) {
+  if let Some(role) = defense::next_defender(room, memory) {
+    defender_for(role, spawn_budget(room))
+  } else if is_city_early(room, memory) {
     Some(CreepMemory::EarlyWorker(EarlyWorker::Idle))
   } else {
     let max_harvesters = max_room_harvesters(room);
@@ -253,36 +377,72 @@ fn pick_next_creep(room: &Room, memory: &Memory) -> Option<CreepMemory> {
     if let Some(source) = harvester_source {
       use harvester::*;
       harvester_assignments_changed_for(&source);
-      Some((Harvester {
-        state: HarvesterState::Harvesting,
-        source: source.id(),
-      }).into())
+      Some(Harvester::new(&source).into())
+    } else if let Some(source) = hauler::source_needing_hauler(room, memory) {
+      cache::invalidate_tag(source.id());
+      Some(hauler::Hauler::new(&source).into())
     } else if num_workers < 10 {
       use worker::*;
       Some(Worker::Idle.into())
-    } else {
+    } else if current_role_count(room, memory, RoleTag::Builder) < MAX_BUILDERS
+        && construction::needs_builders(room, memory) {
+      let segments = builder::builder_segments(spawn_budget(room));
+      Some(builder::Builder::new(segments).into())
+    } else if current_role_count(room, memory, RoleTag::Repairer) < MAX_REPAIRERS
+        && repair::needs_repairers(room) {
+      let segments = repairer::repairer_segments(spawn_budget(room));
+      Some(repairer::Repairer::new(segments).into())
+    } else if let Some(scout) = scout::next_scout(room, memory) {
+      Some(scout.into())
+    } else if let Some(creep_mem) = managers::remote::next_remote_creep(room, memory) {
+      Some(creep_mem)
+    } else if let Some(creep_mem) = colony::next_expansion_creep(room, memory) {
+      Some(creep_mem)
+    } else if let Some(creep_mem) = mineral::next_mineral_creep(room, memory) {
+      Some(creep_mem)
+    } else if upgrader::controller_supplier(room).is_none() {
+      place_controller_container(room);
       None
+    } else {
+      upgrader::next_upgrader(room, memory).map(CreepMemory::from)
     }
   }
 }
 
+/// The most a spawn in `room` can spend on one creep: the energy in every
+/// spawn and extension in the room when they're full. Designs are sized
+/// from this, and it's what `spawn_creep` checks them against.
+pub fn spawn_budget(room: &Room) -> u32 {
+  room.energy_capacity_available()
+}
+
 pub fn spawn_creep(spawn: &StructureSpawn,
                    creep_memory: CreepMemory,
                    memory: &mut Memory) {
-  let spawn_energy = spawn.store().get(ResourceType::Energy).unwrap_or(0);
-  let spawn_capacity = spawn.store().get_capacity(Some(ResourceType::Energy));
-  let design = design_for_memory(&creep_memory, spawn_capacity);
-  let body_cost = design.max_cost(spawn_capacity);
-  info!("spawn energy {} cost {}", spawn_energy, body_cost);
-
-  if spawn_energy < body_cost {
-    info!("need {} more energy for cost {}", body_cost - spawn_energy, body_cost);
+  let Some(room) = spawn.room() else {
+    return
+  };
+  let budget = spawn_budget(&room);
+  let energy = room.energy_available();
+  let design = design_for_memory(&creep_memory, budget);
+  if !design.fits(budget) {
+    warn!("{:?} design costs {} which {} can never afford",
+          creep_memory.tag(), design.max_cost(budget), room.name());
+    return
+  }
+  let body_cost = design.max_cost(budget);
+  info!("spawn energy {} cost {}", energy, body_cost);
+
+  if energy < body_cost {
+    info!("need {} more energy for cost {}", body_cost - energy, body_cost);
   } else {
     let name = memory.creep_name(creep_memory.tag());
-    let body = design.scale(spawn_energy);
+    let body = design.scale(budget);
     match spawn.spawn_creep(&body, &name) {
       Ok(()) => {
         memory.initialize_creep(name, creep_memory);
+        // The role counts for the room are out of date now.
+        cache::invalidate_tag(room.name());
       }
       Err(err) => {
         warn!("Spawn failed: {err:?}");
@@ -291,9 +451,16 @@ pub fn spawn_creep(spawn: &StructureSpawn,
   }
 }
 
+/// Called by the memory GC when a spawn's memory is removed.
+pub fn spawn_removed(id: ObjectId<StructureSpawn>, _memory: SpawnMemory) {
+  cache::invalidate_tag(id);
+}
+
 pub fn spawn_loop(memory: &mut Memory) {
   debug!("did add spawn ext {}", HAS_ADDED_SPAWN_EXT_THIS_TICK.get());
   HAS_ADDED_SPAWN_EXT_THIS_TICK.set(false);
+  let plan_minerals = game::time() % MINERAL_PLAN_INTERVAL == 0;
+  let mut planned_rooms = Vec::new();
   for spawn in game::spawns().values() {
     debug!("running spawn {}", String::from(spawn.name()));
     let mem = memory.spawn_mut(spawn.id()).or_default();
@@ -301,6 +468,11 @@ pub fn spawn_loop(memory: &mut Memory) {
     if !mem.initialized {
       initial_city_construction(&room);
       mem.initialized = true;
+      request_save();
+    }
+    if plan_minerals && !planned_rooms.contains(&room.name()) {
+      planned_rooms.push(room.name());
+      place_mineral_structures(&room);
     }
     if spawn.spawning().is_none() {
       if let Some(creep_mem) = pick_next_creep(&room, memory) {
@@ -0,0 +1,112 @@
+//! Sends claimers and reservers out to the rooms listed for each colony in
+//! `Memory::colonies`.
+
+use log::*;
+use screeps::{prelude::*, game, Room, RoomName};
+
+use crate::creeps::claimer::{self, Claimer, Reserver};
+use crate::creeps::CreepMemory;
+use crate::managers::city;
+use crate::memory::{Expansion, Memory};
+use crate::storage::serialization::request_save;
+use crate::util;
+use crate::util::travel::Travel;
+
+/// Reservers are sent once a reservation has fewer ticks than this left.
+const RESERVE_THRESHOLD: u32 = 1000;
+
+/// Whether a remote room needs a reserver, given how long its reservation
+/// has left if we can see it.
+pub fn needs_reserver(ticks_left: Option<u32>, reservers: u32) -> bool {
+  reservers == 0 && ticks_left.map_or(true, |ticks| ticks < RESERVE_THRESHOLD)
+}
+
+/// Our reservation of a room, or `None` if we can't see it.
+fn reservation_left(target: RoomName) -> Option<u32> {
+  let controller = game::rooms().get(target)?.controller()?;
+  let ours = controller.reservation()
+    .filter(|reservation| util::my_username().as_ref() == Some(&reservation.username()));
+  Some(ours.map_or(0, |reservation| reservation.ticks_to_end()))
+}
+
+fn is_owned(target: RoomName) -> bool {
+  game::rooms().get(target)
+    .and_then(|room| room.controller())
+    .map_or(false, |controller| controller.my())
+}
+
+/// How many rooms we own, to compare against the GCL.
+fn owned_rooms() -> u32 {
+  game::rooms().values()
+    .filter(|room| room.controller().map_or(false, |c| c.my()))
+    .count() as u32
+}
+
+/// The next claimer or reserver `room` should send out, if any.
+pub fn next_expansion_creep(room: &Room, memory: &Memory) -> Option<CreepMemory> {
+  let colony = memory.colony(room.name())?;
+  let max_energy = city::spawn_budget(room);
+  let claimer_cost = claimer::claimer_design().base_cost();
+  if max_energy < claimer_cost {
+    return None
+  }
+  for target in &colony.targets {
+    let assigned = memory.creeps.values().filter(|mem| match (mem, target.expansion) {
+      (CreepMemory::Claimer(claimer), Expansion::Claim) => claimer.target == target.room,
+      (CreepMemory::Reserver(reserver), Expansion::Reserve) => reserver.target == target.room,
+      _ => false,
+    }).count() as u32;
+    match target.expansion {
+      Expansion::Claim => {
+        if assigned == 0 && !is_owned(target.room) && owned_rooms() < game::gcl::level() {
+          return Some(Claimer { home: room.name(), target: target.room, travel: Travel::default() }.into())
+        }
+      }
+      Expansion::Reserve => {
+        if needs_reserver(reservation_left(target.room), assigned) {
+          let segments = claimer::reserver_segments(max_energy);
+          return Some(Reserver { target: target.room, segments, travel: Travel::default() }.into())
+        }
+      }
+    }
+  }
+  None
+}
+
+/// Called once a claimer has claimed `target`, which no longer needs to be
+/// in `home`'s targets.
+pub fn claimed(home: RoomName, target: RoomName, memory: &mut Memory) {
+  let Some(colony) = memory.colony_mut(home) else {
+    warn!("claimed {target} for {home} which is not a colony");
+    return
+  };
+  colony.targets.retain(|t| !(t.room == target && t.expansion == Expansion::Claim));
+  request_save();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reservers_go_when_the_reservation_runs_low() {
+    // can't see the room, so send one to find out.
+    assert!(needs_reserver(None, 0));
+    assert!(needs_reserver(Some(0), 0));
+    assert!(needs_reserver(Some(RESERVE_THRESHOLD - 1), 0));
+    assert!(!needs_reserver(Some(RESERVE_THRESHOLD), 0));
+    assert!(!needs_reserver(Some(10), 1));
+  }
+
+  #[test]
+  fn expansion_creeps_fit_the_room() {
+    let claimer_cost = claimer::claimer_design().base_cost();
+    assert_eq!(claimer_cost, 650);
+    for budget in [650, 800, 1300, 5600] {
+      assert!(claimer::claimer_design().fits(budget));
+      let segments = claimer::reserver_segments(budget);
+      assert!(claimer::reserver_design(segments).fits(budget), "reserver for {budget}");
+    }
+    assert_eq!(claimer::reserver_segments(1300), 2);
+  }
+}
@@ -0,0 +1,206 @@
+//! A per-room queue of construction sites in the order they should be built,
+//! and which sites builders are working on.
+//!
+//! Sites are ranked by what they build (see `site_rank`) and then by how
+//! little progress is left, so nearly finished sites get done first. Each
+//! site takes as many WORK parts as it needs to finish within `BUILD_WINDOW`
+//! ticks, after which builders move on to the next one.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use log::*;
+use priority_queue::PriorityQueue;
+use screeps::local::ObjectId;
+use screeps::{prelude::*, find, game, ConstructionSite, Room, RoomName, StructureType};
+use screeps::constants::BUILD_POWER;
+
+use crate::creeps::CreepMemory;
+use crate::creeps::builder::BuilderState;
+use crate::memory::Memory;
+
+/// How many ticks we want the builders on a site to take to finish it.
+const BUILD_WINDOW: u32 = 100;
+/// How often the sites in a room are looked up again.
+const QUEUE_REFRESH: u32 = 10;
+
+/// Higher is built first.
+pub fn site_rank(structure: StructureType) -> u8 {
+  match structure {
+    StructureType::Spawn => 11,
+    StructureType::Extension => 10,
+    StructureType::Tower => 9,
+    StructureType::Storage => 8,
+    StructureType::Terminal => 7,
+    StructureType::Link => 6,
+    StructureType::Container => 5,
+    StructureType::Extractor => 4,
+    StructureType::Lab => 3,
+    StructureType::Road => 2,
+    StructureType::Rampart => 1,
+    _ => 0,
+  }
+}
+
+/// The WORK parts it takes to build `remaining` progress in `BUILD_WINDOW`.
+pub fn work_needed(remaining: u32) -> u32 {
+  remaining.div_ceil(BUILD_POWER * BUILD_WINDOW).max(1)
+}
+
+type SitePriority = (u8, Reverse<u32>);
+
+struct ConstructionQueue {
+  refreshed_at: u32,
+  sites: PriorityQueue<ObjectId<ConstructionSite>, SitePriority>,
+}
+
+impl ConstructionQueue {
+  fn survey(room: &Room, time: u32) -> ConstructionQueue {
+    let sites = room.find(find::MY_CONSTRUCTION_SITES, None)
+      .into_iter()
+      .filter_map(|site| {
+        let remaining = site.progress_total().saturating_sub(site.progress());
+        let priority = (site_rank(site.structure_type()), Reverse(remaining));
+        site.try_id().map(|id| (id, priority))
+      })
+      .collect();
+    ConstructionQueue { refreshed_at: time, sites }
+  }
+}
+
+thread_local! {
+  static QUEUES: RefCell<HashMap<RoomName, ConstructionQueue>> = RefCell::new(HashMap::new());
+  /// The WORK parts assigned to each site. `None` until it is rebuilt from
+  /// the builders in memory after a reset.
+  static ASSIGNED: RefCell<Option<HashMap<ObjectId<ConstructionSite>, u32>>> =
+    RefCell::new(None);
+}
+
+/// The first site in priority order that still needs more WORK parts than it
+/// has assigned.
+fn pick_site<K: Hash + Eq + Clone>(
+  sites: &PriorityQueue<K, SitePriority>,
+  assigned: &HashMap<K, u32>,
+) -> Option<K> {
+  sites.clone()
+    .into_sorted_iter()
+    .find(|(site, (_, Reverse(remaining)))| {
+      assigned.get(site).copied().unwrap_or(0) < work_needed(*remaining)
+    })
+    .map(|(site, _)| site)
+}
+
+fn with_queue<R>(room: &Room, f: impl FnOnce(&ConstructionQueue) -> R) -> R {
+  let time = game::time();
+  QUEUES.with(|queues| {
+    let mut queues = queues.borrow_mut();
+    let queue = queues.entry(room.name())
+      .or_insert_with(|| ConstructionQueue::survey(room, time));
+    if queue.refreshed_at + QUEUE_REFRESH <= time {
+      *queue = ConstructionQueue::survey(room, time);
+    }
+    f(queue)
+  })
+}
+
+fn with_assigned<R>(
+  memory: &Memory, f: impl FnOnce(&mut HashMap<ObjectId<ConstructionSite>, u32>) -> R
+) -> R {
+  ASSIGNED.with(|assigned| {
+    let mut assigned = assigned.borrow_mut();
+    let assigned = assigned.get_or_insert_with(|| {
+      let mut rebuilt = HashMap::new();
+      for mem in memory.creeps.values() {
+        if let CreepMemory::Builder(builder) = mem {
+          if let Some(site) = builder.state.site() {
+            *rebuilt.entry(site).or_insert(0) += builder.work_parts as u32;
+          }
+        }
+      }
+      rebuilt
+    });
+    f(assigned)
+  })
+}
+
+/// The most important site in the room, whether or not it has builders.
+pub fn top_site(room: &Room) -> Option<ObjectId<ConstructionSite>> {
+  with_queue(room, |queue| queue.sites.peek().map(|(site, _)| *site))
+}
+
+/// Whether any of the creeps in the room are builders, which take over
+/// building from the workers.
+pub fn has_builders(room: &Room, memory: &Memory) -> bool {
+  room.find(find::MY_CREEPS, None)
+    .iter()
+    .any(|creep| matches!(memory.creep(creep), Some(CreepMemory::Builder(_))))
+}
+
+/// Assign `work_parts` to the most important site that needs them.
+pub fn assign_site(
+  room: &Room, work_parts: u32, memory: &Memory
+) -> Option<ObjectId<ConstructionSite>> {
+  let site = with_assigned(memory, |assigned| {
+    let site = with_queue(room, |queue| pick_site(&queue.sites, assigned))?;
+    *assigned.entry(site).or_insert(0) += work_parts;
+    Some(site)
+  });
+  debug!("assigned {work_parts} WORK parts to site {site:?}");
+  site
+}
+
+/// Take `work_parts` off of a site, when a builder finishes or dies.
+pub fn release_site(site: ObjectId<ConstructionSite>, work_parts: u32) {
+  ASSIGNED.with(|assigned| {
+    if let Some(assigned) = assigned.borrow_mut().as_mut() {
+      if let Some(work) = assigned.get_mut(&site) {
+        *work = work.saturating_sub(work_parts);
+        if *work == 0 {
+          assigned.remove(&site);
+        }
+      }
+    }
+  })
+}
+
+/// Whether there's a site in the room that needs more WORK parts.
+pub fn needs_builders(room: &Room, memory: &Memory) -> bool {
+  with_assigned(memory, |assigned| {
+    with_queue(room, |queue| pick_site(&queue.sites, assigned).is_some())
+  })
+}
+
+/// Look up the sites in the room again next time, e.g. after placing one.
+pub fn sites_changed(room: &Room) {
+  QUEUES.with(|queues| queues.borrow_mut().remove(&room.name()));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sites_are_picked_by_rank_then_progress() {
+    let mut sites: PriorityQueue<u32, SitePriority> = PriorityQueue::new();
+    sites.push(1, (site_rank(StructureType::Road), Reverse(300)));
+    sites.push(2, (site_rank(StructureType::Extension), Reverse(3000)));
+    sites.push(3, (site_rank(StructureType::Extension), Reverse(500)));
+    let mut assigned = HashMap::new();
+    assert_eq!(pick_site(&sites, &assigned), Some(3));
+    // the almost finished extension only needs one WORK part.
+    assigned.insert(3, 1);
+    assert_eq!(pick_site(&sites, &assigned), Some(2));
+    assigned.insert(2, work_needed(3000));
+    assert_eq!(work_needed(3000), 6);
+    assert_eq!(pick_site(&sites, &assigned), Some(1));
+    assigned.insert(1, 1);
+    assert_eq!(pick_site(&sites, &assigned), None);
+
+    for structure in [StructureType::Terminal, StructureType::Link, StructureType::Extractor,
+                      StructureType::Lab] {
+      assert!(site_rank(structure) > site_rank(StructureType::Road));
+    }
+  }
+}
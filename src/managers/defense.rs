@@ -0,0 +1,162 @@
+//! Picks targets for defenders and decides which defender to spawn when
+//! hostiles come into one of our rooms.
+
+use std::cmp::Reverse;
+
+use screeps::{prelude::*, find, Creep, Part, Position, Room, StructureObject};
+
+use crate::creeps::{CreepMemory, RoleTag};
+use crate::memory::Memory;
+
+/// Most defenders of all kinds a room will spawn at once.
+const MAX_DEFENDERS: u32 = 6;
+
+/// The combat parts on one side of a fight.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Strength {
+  pub attack: u32,
+  pub ranged: u32,
+  pub heal: u32,
+}
+
+impl Strength {
+  pub fn of(creep: &Creep) -> Strength {
+    Strength {
+      attack: creep.get_active_bodyparts(Part::Attack) as u32,
+      ranged: creep.get_active_bodyparts(Part::RangedAttack) as u32,
+      heal: creep.get_active_bodyparts(Part::Heal) as u32,
+    }
+  }
+
+  pub fn total(&self) -> u32 {
+    self.attack + self.ranged + self.heal
+  }
+}
+
+impl std::ops::Add for Strength {
+  type Output = Strength;
+
+  fn add(self, other: Strength) -> Strength {
+    Strength {
+      attack: self.attack + other.attack,
+      ranged: self.ranged + other.ranged,
+      heal: self.heal + other.heal,
+    }
+  }
+}
+
+impl std::iter::Sum for Strength {
+  fn sum<I: Iterator<Item = Strength>>(iter: I) -> Strength {
+    iter.fold(Strength::default(), |a, b| a + b)
+  }
+}
+
+/// The defender to spawn next, if we are weaker than the hostiles.
+///
+/// Healers only come once there's someone fighting for them to heal, and
+/// hostiles that can shoot back are met with ranged defenders.
+pub fn next_defender_role(hostiles: Strength, ours: Strength, defenders: u32) -> Option<RoleTag> {
+  if hostiles.total() == 0 || ours.total() >= hostiles.total() || defenders >= MAX_DEFENDERS {
+    return None
+  }
+  if hostiles.heal > 0 && ours.heal == 0 && ours.attack + ours.ranged > 0 {
+    Some(RoleTag::Healer)
+  } else if hostiles.ranged > 0 {
+    Some(RoleTag::RangedDefender)
+  } else {
+    Some(RoleTag::Defender)
+  }
+}
+
+fn is_defender(memory: &CreepMemory) -> bool {
+  matches!(memory.tag(), RoleTag::Defender | RoleTag::RangedDefender | RoleTag::Healer)
+}
+
+/// The defender the room should spawn to deal with the hostiles in it.
+pub fn next_defender(room: &Room, memory: &Memory) -> Option<RoleTag> {
+  let hostiles: Strength = room.find(find::HOSTILE_CREEPS, None)
+    .iter()
+    .map(Strength::of)
+    .sum();
+  if hostiles.total() == 0 {
+    return None
+  }
+  let defenders: Vec<Creep> = room.find(find::MY_CREEPS, None)
+    .into_iter()
+    .filter(|creep| memory.creep(creep).map_or(false, is_defender))
+    .collect();
+  let ours = defenders.iter().map(Strength::of).sum();
+  next_defender_role(hostiles, ours, defenders.len() as u32)
+}
+
+/// How far out from our spawns our ramparts are. Hostiles closer in than
+/// that have gotten inside.
+fn rampart_line(room: &Room) -> Option<(Position, u32)> {
+  let spawn = room.find(find::MY_SPAWNS, None).into_iter().next()?;
+  let range = room.find(find::MY_STRUCTURES, None)
+    .into_iter()
+    .filter(|structure| matches!(structure, StructureObject::StructureRampart(_)))
+    .map(|rampart| rampart.pos().get_range_to(spawn.pos()))
+    .min()?;
+  Some((spawn.pos(), range))
+}
+
+/// Higher is attacked first: healers, then hostiles inside our ramparts,
+/// then the closest.
+pub fn target_priority(heals: bool, inside: bool, range: u32) -> (bool, bool, Reverse<u32>) {
+  (heals, inside, Reverse(range))
+}
+
+/// The hostile in the room a defender at `pos` should attack.
+pub fn pick_target(room: &Room, pos: Position) -> Option<Creep> {
+  let line = rampart_line(room);
+  room.find(find::HOSTILE_CREEPS, None)
+    .into_iter()
+    .max_by_key(|hostile| {
+      let heals = hostile.get_active_bodyparts(Part::Heal) > 0;
+      let inside = line.map_or(false, |(spawn, range)| {
+        hostile.pos().get_range_to(spawn) < range
+      });
+      target_priority(heals, inside, hostile.pos().get_range_to(pos))
+    })
+}
+
+/// The most damaged of our creeps in the room, for healers.
+pub fn pick_patient(room: &Room) -> Option<Creep> {
+  room.find(find::MY_CREEPS, None)
+    .into_iter()
+    .filter(|creep| creep.hits() < creep.hits_max())
+    .max_by_key(|creep| creep.hits_max() - creep.hits())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defenders_answer_the_threat() {
+    let melee = Strength { attack: 4, ranged: 0, heal: 0 };
+    let ranged = Strength { attack: 0, ranged: 3, heal: 0 };
+    let healer = Strength { attack: 0, ranged: 0, heal: 2 };
+    assert_eq!(next_defender_role(Strength::default(), Strength::default(), 0), None);
+    assert_eq!(next_defender_role(melee, Strength::default(), 0), Some(RoleTag::Defender));
+    assert_eq!(next_defender_role(melee + ranged, Strength::default(), 0),
+               Some(RoleTag::RangedDefender));
+    // nobody to heal yet.
+    assert_eq!(next_defender_role(melee + healer, Strength::default(), 0),
+               Some(RoleTag::Defender));
+    assert_eq!(next_defender_role(melee + healer, melee, 1), Some(RoleTag::Healer));
+    assert_eq!(next_defender_role(melee, melee, 1), None);
+    assert_eq!(next_defender_role(melee + ranged, Strength::default(), MAX_DEFENDERS), None);
+
+    // healers first, then hostiles inside, then the closest.
+    let mut targets = vec![
+      target_priority(false, false, 1),
+      target_priority(false, true, 10),
+      target_priority(true, false, 20),
+    ];
+    targets.sort();
+    assert_eq!(targets.last(), Some(&target_priority(true, false, 20)));
+    assert_eq!(targets[1], target_priority(false, true, 10));
+  }
+}
@@ -1 +1,6 @@
 pub mod city;
+pub mod colony;
+pub mod construction;
+pub mod defense;
+pub mod repair;
+pub mod remote;
@@ -0,0 +1,307 @@
+//! Remote mining: harvesting sources in the rooms next to a colony and
+//! hauling the energy home.
+//!
+//! Operations are planned for neighboring rooms while we have vision of them,
+//! e.g. when a scout passes through, skipping rooms the intel says are taken.
+//! Each room planned gets a `Reserve` target so reservers double its sources.
+//! An operation is dropped when its room turns out not to be worth it: hostiles,
+//! an invader core, or someone else reserving it. The room is then left
+//! alone for `DROP_COOLDOWN` ticks.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{prelude::*, find, game, Room, RoomName, StructureObject};
+use screeps::constants::{SOURCE_ENERGY_CAPACITY, SOURCE_ENERGY_NEUTRAL_CAPACITY, ENERGY_REGEN_TIME};
+
+use crate::creeps::hauler::{self, STEP_TIME};
+use crate::creeps::harvester::{keeper_lairs, source_container};
+use crate::creeps::remote::{RemoteHarvester, RemoteHauler};
+use crate::creeps::CreepMemory;
+use crate::managers::city;
+use crate::managers::defense::Strength;
+use crate::memory::{ColonyMemory, ColonyTarget, Expansion, Memory, RemoteOperation};
+use crate::memory::intel::RoomIntel;
+use crate::storage::serialization::request_save;
+use crate::util::{self, travel};
+
+/// Most rooms a colony mines remotely.
+const MAX_REMOTE_ROOMS: usize = 2;
+/// Sources further than this from the drop off aren't worth hauling from.
+const MAX_REMOTE_DISTANCE: usize = 120;
+/// How often new rooms are planned and paths looked up again. Rooms that
+/// come into view in between are planned as soon as their intel is recorded.
+const REMOTE_REFRESH: u32 = 100;
+/// How long a dropped room is left alone.
+const DROP_COOLDOWN: u32 = 1500;
+
+/// Why a remote room isn't worth mining.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hazard {
+  Hostiles,
+  InvaderCore,
+  ReservedByOthers,
+}
+
+thread_local! {
+  /// Rooms that were dropped, and the tick they can be planned again.
+  static AVOID: RefCell<HashMap<RoomName, u32>> = RefCell::new(HashMap::new());
+}
+
+fn avoided(room: RoomName, time: u32) -> bool {
+  AVOID.with(|avoid| avoid.borrow().get(&room).map_or(false, |until| *until > time))
+}
+
+/// What makes a room not worth mining right now, if anything.
+pub fn hazard(room: &Room, username: Option<&str>) -> Option<Hazard> {
+  let hostiles: Strength = room.find(find::HOSTILE_CREEPS, None)
+    .iter()
+    .map(Strength::of)
+    .sum();
+  if hostiles.total() > 0 {
+    return Some(Hazard::Hostiles)
+  }
+  let core = room.find(find::HOSTILE_STRUCTURES, None)
+    .iter()
+    .any(|structure| matches!(structure, StructureObject::StructureInvaderCore(_)));
+  if core {
+    return Some(Hazard::InvaderCore)
+  }
+  let reservation = room.controller().and_then(|controller| controller.reservation());
+  match reservation {
+    Some(reservation) if Some(reservation.username().as_str()) != username =>
+      Some(Hazard::ReservedByOthers),
+    _ => None,
+  }
+}
+
+/// Energy per tick a remote source makes, assuming we keep it reserved.
+fn remote_income(reserved: bool) -> f64 {
+  let capacity = if reserved { SOURCE_ENERGY_CAPACITY } else { SOURCE_ENERGY_NEUTRAL_CAPACITY };
+  capacity as f64 / ENERGY_REGEN_TIME as f64
+}
+
+/// Whether haulers carrying `capacity` between them fall short of an
+/// operation with a path of `distance`.
+pub fn needs_remote_hauling(capacity: u32, distance: usize, reserved: bool) -> bool {
+  hauler::needs_more_hauling(capacity, distance as u32, STEP_TIME, remote_income(reserved))
+}
+
+fn is_reserved_by_colony(colony: &ColonyMemory, room: RoomName) -> bool {
+  colony.targets.iter()
+    .any(|target| target.room == room && target.expansion == Expansion::Reserve)
+}
+
+/// Whether a neighboring room is one we could mine.
+fn can_mine(room: &Room, username: Option<&str>) -> bool {
+  let Some(controller) = room.controller() else {
+    // highways and source keeper rooms.
+    return false
+  };
+  let keepers = keeper_lairs(room.find(find::HOSTILE_STRUCTURES, None)).next().is_some();
+  controller.owner().is_none() && !keepers && hazard(room, username).is_none()
+}
+
+/// Whether a neighboring room should be looked at for mining this tick: on
+/// every refresh, or when its intel was just recorded because it came into
+/// view.
+fn due_for_planning(intel: Option<&RoomIntel>, time: u32) -> bool {
+  time % REMOTE_REFRESH == 0 || intel.map_or(false, |intel| intel.last_seen == time)
+}
+
+/// Add operations for the sources in one neighboring room, if there's one
+/// we can see and mine.
+fn plan_operations(
+  home: &Room,
+  colony: &mut ColonyMemory,
+  intel: &HashMap<RoomName, RoomIntel>,
+  username: Option<&str>,
+  time: u32,
+) {
+  let mut rooms: Vec<RoomName> = colony.remotes.iter()
+    .map(|op| op.source_pos.room_name())
+    .collect();
+  rooms.dedup();
+  if rooms.len() >= MAX_REMOTE_ROOMS {
+    return
+  }
+  let Some(drop_off) = hauler::drop_off_pos(home) else {
+    return
+  };
+  let neighbors = game::map::describe_exits(home.name());
+  for name in neighbors.values() {
+    if rooms.contains(&name) || avoided(name, time) || !due_for_planning(intel.get(&name), time) {
+      continue
+    }
+    let known_bad = intel.get(&name)
+      .map_or(false, |intel| intel.is_taken(username) || !intel.keeper_lairs.is_empty());
+    if known_bad {
+      continue
+    }
+    let Some(room) = game::rooms().get(name) else {
+      continue
+    };
+    if !can_mine(&room, username) {
+      continue
+    }
+    let operations: Vec<RemoteOperation> = room.find(find::SOURCES, None)
+      .into_iter()
+      .filter_map(|source| {
+        let path = travel::plan(source.pos(), drop_off, 1);
+        if path.is_empty() || path.len() > MAX_REMOTE_DISTANCE {
+          return None
+        }
+        Some(RemoteOperation {
+          source: source.id(),
+          source_pos: source.pos(),
+          container: source_container(&source).map(|cont| cont.id()),
+          path,
+          harvesters: Vec::new(),
+          haulers: Vec::new(),
+        })
+      })
+      .collect();
+    if operations.is_empty() {
+      continue
+    }
+    info!("{} is remote mining {} sources in {name}", home.name(), operations.len());
+    colony.remotes.extend(operations);
+    if !is_reserved_by_colony(colony, name) {
+      colony.targets.push(ColonyTarget { room: name, expansion: Expansion::Reserve });
+    }
+    request_save();
+    return
+  }
+}
+
+/// Drop the operations in rooms that have become too dangerous or been
+/// taken.
+fn drop_hazardous(colony: &mut ColonyMemory, username: Option<&str>, time: u32) {
+  let mut dropped = Vec::new();
+  colony.remotes.retain(|op| {
+    let name = op.source_pos.room_name();
+    let Some(room) = game::rooms().get(name) else {
+      return true
+    };
+    match hazard(&room, username) {
+      Some(hazard) => {
+        info!("dropping remote mining in {name} for {}: {hazard:?}", colony.home);
+        dropped.push(name);
+        false
+      }
+      None => true,
+    }
+  });
+  if dropped.is_empty() {
+    return
+  }
+  AVOID.with(|avoid| {
+    let mut avoid = avoid.borrow_mut();
+    for &name in &dropped {
+      avoid.insert(name, time + DROP_COOLDOWN);
+    }
+  });
+  colony.targets.retain(|target| {
+    !(target.expansion == Expansion::Reserve && dropped.contains(&target.room))
+  });
+  request_save();
+}
+
+/// Look up the container and path of an operation again, now that the
+/// source is visible or the path may have changed.
+fn survey(op: &mut RemoteOperation, home: &Room) {
+  let Some(source) = op.source.resolve() else {
+    return
+  };
+  op.container = source_container(&source).map(|cont| cont.id());
+  if let Some(drop_off) = hauler::drop_off_pos(home) {
+    op.path = travel::plan(source.pos(), drop_off, 1);
+  }
+}
+
+/// Plan, survey and drop remote operations, and record the creeps working
+/// each one. Runs before spawning so it sees this tick's assignments.
+pub fn remote_loop(memory: &mut Memory) {
+  let time = game::time();
+  let username = util::my_username();
+  let username = username.as_deref();
+  let mut harvesters: HashMap<_, Vec<String>> = HashMap::new();
+  let mut haulers: HashMap<_, Vec<String>> = HashMap::new();
+  for (name, mem) in &memory.creeps {
+    match mem {
+      CreepMemory::RemoteHarvester(harvester) =>
+        harvesters.entry(harvester.source).or_default().push(name.clone()),
+      CreepMemory::RemoteHauler(hauler) =>
+        haulers.entry(hauler.source).or_default().push(name.clone()),
+      _ => (),
+    }
+  }
+  let any_due = due_for_planning(None, time)
+    || memory.intel.values().any(|intel| due_for_planning(Some(intel), time));
+  for colony in memory.colonies.iter_mut() {
+    let Some(home) = game::rooms().get(colony.home) else {
+      continue
+    };
+    if !home.controller().map_or(false, |c| c.my()) {
+      continue
+    }
+    drop_hazardous(colony, username, time);
+    if any_due {
+      plan_operations(&home, colony, &memory.intel, username, time);
+    }
+    for op in colony.remotes.iter_mut() {
+      if op.path.is_empty() || time % REMOTE_REFRESH == 0 {
+        survey(op, &home);
+      }
+      op.harvesters = harvesters.remove(&op.source).unwrap_or_default();
+      op.haulers = haulers.remove(&op.source).unwrap_or_default();
+    }
+  }
+}
+
+/// The next creep `room` should spawn for its remote operations.
+pub fn next_remote_creep(room: &Room, memory: &Memory) -> Option<CreepMemory> {
+  let colony = memory.colony(room.name())?;
+  let max_energy = city::spawn_budget(room);
+  for op in &colony.remotes {
+    if op.harvesters.is_empty() {
+      return Some(RemoteHarvester::new(op, max_energy).into())
+    }
+    if op.path.is_empty() {
+      continue
+    }
+    let reserved = is_reserved_by_colony(colony, op.source_pos.room_name());
+    let capacity = hauler::carry_capacity(op.haulers.iter());
+    if needs_remote_hauling(capacity, op.path.len(), reserved) {
+      return Some(RemoteHauler::new(op, room.name()).into())
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn remote_haulers_follow_distance_and_reservation() {
+    // five CARRY+MOVE carry 250 over a 50 tile path: 2.5 energy a tick each.
+    assert!(needs_remote_hauling(250, 50, false));
+    assert!(!needs_remote_hauling(2 * 250, 50, false));
+    assert!(needs_remote_hauling(3 * 250, 50, true));
+    assert!(!needs_remote_hauling(4 * 250, 50, true));
+    assert!(needs_remote_hauling(7 * 250, 100, true));
+    // one big hauler does as well as several small ones.
+    assert!(!needs_remote_hauling(1000, 50, true));
+  }
+
+  #[test]
+  fn rooms_are_planned_on_refresh_or_when_seen() {
+    let seen = RoomIntel { last_seen: 150, ..Default::default() };
+    assert!(due_for_planning(Some(&seen), 150));
+    assert!(!due_for_planning(Some(&seen), 151));
+    assert!(!due_for_planning(None, 151));
+    assert!(due_for_planning(None, REMOTE_REFRESH * 3));
+  }
+}
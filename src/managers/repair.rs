@@ -0,0 +1,214 @@
+//! Keeps structures from decaying away.
+//!
+//! Each room has a queue of structures below their hit target, ordered by how
+//! many ticks they have left before decay destroys them. Towers with energy
+//! to spare repair the front of the queue, and `Repairer` creeps take the
+//! rest.
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use log::*;
+use priority_queue::PriorityQueue;
+use screeps::local::ObjectId;
+use screeps::{
+  prelude::*, find, game, ResourceType, Room, RoomName, Structure, StructureObject,
+  StructureTower, StructureType,
+};
+use screeps::constants::{
+  CONTAINER_DECAY, CONTAINER_DECAY_TIME, CONTAINER_DECAY_TIME_OWNED, RAMPART_DECAY_AMOUNT,
+  RAMPART_DECAY_TIME, ROAD_DECAY_AMOUNT, ROAD_DECAY_TIME, TOWER_CAPACITY,
+};
+
+use crate::log_warn;
+
+/// How often the structures in a room are looked over again.
+const REPAIR_REFRESH: u32 = 50;
+/// Structures are queued once they fall below this fraction of their target,
+/// so we repair in batches instead of topping up every tick.
+const REPAIR_THRESHOLD: f64 = 0.75;
+/// Towers keep this much energy for defending.
+const TOWER_RESERVE: u32 = TOWER_CAPACITY / 2;
+
+/// Average hits lost per tick to decay.
+pub fn decay_per_tick(structure: StructureType, owned_room: bool) -> f64 {
+  match structure {
+    StructureType::Road => ROAD_DECAY_AMOUNT as f64 / ROAD_DECAY_TIME as f64,
+    StructureType::Container if owned_room =>
+      CONTAINER_DECAY as f64 / CONTAINER_DECAY_TIME_OWNED as f64,
+    StructureType::Container => CONTAINER_DECAY as f64 / CONTAINER_DECAY_TIME as f64,
+    StructureType::Rampart => RAMPART_DECAY_AMOUNT as f64 / RAMPART_DECAY_TIME as f64,
+    _ => 0.0,
+  }
+}
+
+/// The hits we keep a structure at. Walls and ramparts have far more hits
+/// than we can afford early on, so they are built up as the room grows.
+pub fn hit_target(structure: StructureType, hits_max: u32, level: u8) -> u32 {
+  match structure {
+    StructureType::Rampart | StructureType::Wall => {
+      let target = match level {
+        0..=1 => 0,
+        2 => 10_000,
+        3 => 30_000,
+        4 => 100_000,
+        5 => 300_000,
+        6 => 1_000_000,
+        7 => 3_000_000,
+        _ => 10_000_000,
+      };
+      target.min(hits_max)
+    }
+    _ => hits_max,
+  }
+}
+
+/// Whether a structure has fallen far enough below its target to repair.
+pub fn needs_repair(hits: u32, target: u32) -> bool {
+  (hits as f64) < target as f64 * REPAIR_THRESHOLD
+}
+
+/// Ticks until decay destroys a structure, used to order the queue.
+/// Structures that don't decay are ordered by their hits.
+fn ticks_left(hits: u32, decay: f64) -> u32 {
+  if decay > 0.0 {
+    (hits as f64 / decay) as u32
+  } else {
+    hits
+  }
+}
+
+struct RepairQueue {
+  refreshed_at: u32,
+  structures: PriorityQueue<ObjectId<Structure>, Reverse<u32>>,
+}
+
+impl RepairQueue {
+  fn survey(room: &Room, time: u32) -> RepairQueue {
+    let level = room.controller().map_or(0, |c| c.level());
+    let owned = room.controller().map_or(false, |c| c.my());
+    let structures = room.find(find::STRUCTURES, None)
+      .into_iter()
+      .filter(|structure| match structure {
+        // only ours, or the unowned roads and containers we use.
+        StructureObject::StructureRoad(_) | StructureObject::StructureContainer(_) => true,
+        StructureObject::StructureWall(_) => owned,
+        other => other.as_owned().map_or(false, |s| s.my()),
+      })
+      .filter_map(|structure| {
+        let structure = structure.as_structure();
+        let kind = structure.structure_type();
+        let target = hit_target(kind, structure.hits_max(), level);
+        if !needs_repair(structure.hits(), target) {
+          return None
+        }
+        let left = ticks_left(structure.hits(), decay_per_tick(kind, owned));
+        Some((structure.id(), Reverse(left)))
+      })
+      .collect();
+    RepairQueue { refreshed_at: time, structures }
+  }
+}
+
+thread_local! {
+  static QUEUES: RefCell<HashMap<RoomName, RepairQueue>> = RefCell::new(HashMap::new());
+}
+
+fn with_queue<R>(room: &Room, f: impl FnOnce(&mut RepairQueue) -> R) -> R {
+  let time = game::time();
+  QUEUES.with(|queues| {
+    let mut queues = queues.borrow_mut();
+    let queue = queues.entry(room.name())
+      .or_insert_with(|| RepairQueue::survey(room, time));
+    if queue.refreshed_at + REPAIR_REFRESH <= time {
+      *queue = RepairQueue::survey(room, time);
+    }
+    f(queue)
+  })
+}
+
+/// The hits to repair a structure up to.
+pub fn target_for(structure: &Structure) -> u32 {
+  let level = structure.room().and_then(|room| room.controller()).map_or(0, |c| c.level());
+  hit_target(structure.structure_type(), structure.hits_max(), level)
+}
+
+/// The most urgent structure to repair. It stays at the front of the queue
+/// until it's back up to its target, so towers and repairers keep at it.
+pub fn next_repair(room: &Room) -> Option<Structure> {
+  with_queue(room, |queue| {
+    while let Some((id, _)) = queue.structures.peek() {
+      match id.resolve() {
+        Some(structure) if structure.hits() < target_for(&structure) =>
+          return Some(structure),
+        // it's done, or gone.
+        _ => {
+          let id = *id;
+          queue.structures.remove(&id);
+        }
+      }
+    }
+    None
+  })
+}
+
+/// Towers with more energy than they keep for defense.
+fn towers_with_spare_energy(room: &Room) -> Vec<StructureTower> {
+  room.find(find::MY_STRUCTURES, None)
+    .into_iter()
+    .filter_map(|structure| match structure {
+      StructureObject::StructureTower(tower) => Some(tower),
+      _ => None,
+    })
+    .filter(|tower| tower.store().get_used_capacity(Some(ResourceType::Energy)) > TOWER_RESERVE)
+    .collect()
+}
+
+/// Whether the room needs a repairer, which is when there's something to
+/// repair and no tower can spare the energy for it.
+pub fn needs_repairers(room: &Room) -> bool {
+  with_queue(room, |queue| !queue.structures.is_empty())
+    && towers_with_spare_energy(room).is_empty()
+}
+
+/// Towers with energy to spare repair the most urgent structures.
+pub fn run_towers(room: &Room) {
+  for tower in towers_with_spare_energy(room) {
+    let Some(target) = next_repair(room) else {
+      break
+    };
+    debug!("tower repairing {:?} at {} hits", target.structure_type(), target.hits());
+    log_warn!(tower.repair(&target), err => "Tower could not repair: {err:?}");
+  }
+}
+
+pub fn repair_loop() {
+  for room in game::rooms().values() {
+    if room.controller().map_or(false, |c| c.my()) {
+      run_towers(&room);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn repair_targets_follow_decay_and_level() {
+    // a road loses 100 hits every 1000 ticks.
+    assert_eq!(decay_per_tick(StructureType::Road, true), 0.1);
+    assert_eq!(ticks_left(2500, decay_per_tick(StructureType::Road, true)), 25_000);
+    // containers decay five times slower in owned rooms.
+    assert_eq!(decay_per_tick(StructureType::Container, false),
+               5.0 * decay_per_tick(StructureType::Container, true));
+
+    assert_eq!(hit_target(StructureType::Rampart, 300_000, 2), 10_000);
+    assert_eq!(hit_target(StructureType::Rampart, 300_000, 8), 300_000);
+    assert_eq!(hit_target(StructureType::Road, 5000, 1), 5000);
+    assert!(needs_repair(3000, 5000));
+    assert!(!needs_repair(4000, 5000));
+    assert!(!needs_repair(0, hit_target(StructureType::Wall, 300_000_000, 1)));
+  }
+}
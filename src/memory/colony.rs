@@ -0,0 +1,53 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use screeps::local::ObjectId;
+use screeps::{Position, RoomName, Source, StructureContainer};
+use crate::storage::cbor;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Persist, Serialize, Deserialize)]
+pub enum Expansion {
+  /// Claim the controller so it becomes a colony of its own.
+  #[persist(0)] Claim,
+  /// Keep the controller reserved, for remote rooms.
+  #[persist(1)] Reserve,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Persist, Serialize, Deserialize)]
+pub struct ColonyTarget {
+  #[persist(0, "cbor::room_name")]
+  pub room: RoomName,
+  #[persist(1)] pub expansion: Expansion,
+}
+
+/// The rooms an owned room sends claimers and reservers to.
+#[derive(Clone, Debug, PartialEq, Eq, Persist, Serialize, Deserialize)]
+pub struct ColonyMemory {
+  #[persist(0, "cbor::room_name")]
+  pub home: RoomName,
+  #[persist(1)] pub targets: Vec<ColonyTarget>,
+  #[persist(2)] #[serde(default)]
+  pub remotes: Vec<RemoteOperation>,
+}
+
+/// Mining a source in a room next to the colony and hauling the energy home.
+///
+/// Only the source is persisted. The rest is looked up again by
+/// `managers::remote` when it has vision of the room.
+#[derive(Clone, Debug, PartialEq, Eq, Persist, Serialize, Deserialize)]
+pub struct RemoteOperation {
+  #[persist(0, "cbor::object_id")]
+  pub source: ObjectId<Source>,
+  /// Where the source is, for travelling there without vision.
+  #[persist(1, "cbor::position")]
+  pub source_pos: Position,
+  #[serde(default)]
+  pub container: Option<ObjectId<StructureContainer>>,
+  /// The path from next to the source back to where haulers drop off.
+  #[serde(default)]
+  pub path: Vec<Position>,
+  /// Names of the creeps working it, refreshed every tick.
+  #[serde(default)]
+  pub harvesters: Vec<String>,
+  #[serde(default)]
+  pub haulers: Vec<String>,
+}
@@ -0,0 +1,144 @@
+//! Prunes entries from `Memory` whose game object no longer exists.
+//!
+//! Every map keyed by a creep name or an `ObjectId` is reconciled against the
+//! game, a batch of `GC_BATCH` keys per tick, cycling through the maps. Each
+//! removed entry is handed to the subsystem that owns it so it can release
+//! whatever it was holding on to.
+//!
+//! This is also where dead creeps are removed and `creeps::creep_removed` is
+//! called for them. The GC runs before anything else in the tick, so a creep
+//! whose memory was added when it started spawning last tick is already in
+//! `game::creeps()`.
+
+use std::cell::Cell;
+
+use log::*;
+use screeps::game;
+use screeps::prelude::*;
+
+use super::Memory;
+use crate::creeps;
+use crate::creeps::harvester;
+use crate::managers::city;
+
+/// How many keys are checked per tick.
+const GC_BATCH: usize = 20;
+
+/// The maps in `Memory` that are garbage collected, in the order they're
+/// visited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+  Creeps,
+  Spawns,
+  Sources,
+}
+
+impl Stage {
+  fn next(self) -> Stage {
+    match self {
+      Stage::Creeps => Stage::Spawns,
+      Stage::Spawns => Stage::Sources,
+      Stage::Sources => Stage::Creeps,
+    }
+  }
+}
+
+thread_local! {
+  /// The map being collected and how far through it we are.
+  static GC_CURSOR: Cell<(Stage, usize)> = Cell::new((Stage::Creeps, 0));
+}
+
+/// Check the next `batch` keys from `start` and return the ones that are no
+/// longer live, along with where the next batch starts. `None` means the map
+/// is finished and the next one should be collected.
+fn dead_keys<'a, K: Clone + 'a>(
+  keys: impl Iterator<Item = &'a K>,
+  start: usize,
+  batch: usize,
+  is_live: impl Fn(&K) -> bool,
+) -> (Vec<K>, Option<usize>) {
+  let mut checked = 0;
+  let dead = keys
+    .skip(start)
+    .take(batch)
+    .inspect(|_| checked += 1)
+    .filter(|key| !is_live(key))
+    .cloned()
+    .collect::<Vec<_>>();
+  let next = if checked < batch {
+    None
+  } else {
+    // the removed keys shift everything after them forward.
+    Some(start + checked - dead.len())
+  };
+  (dead, next)
+}
+
+/// Collect the next batch of keys.
+pub fn collect_garbage(memory: &mut Memory) {
+  let (stage, start) = GC_CURSOR.with(Cell::get);
+  let next = match stage {
+    Stage::Creeps => {
+      let game_creeps = game::creeps();
+      let (dead, next) = dead_keys(memory.creeps.keys(), start, GC_BATCH,
+                                   |name| game_creeps.get(name.clone()).is_some());
+      for name in dead {
+        if let Some(creep_memory) = memory.creeps.remove(&name) {
+          info!("removing memory for dead creep {name}");
+          creeps::creep_removed(&name, creep_memory, memory);
+        }
+      }
+      next
+    }
+    Stage::Spawns => {
+      let (dead, next) = dead_keys(memory.spawns.keys(), start, GC_BATCH,
+                                   |id| id.resolve().is_some());
+      for id in dead {
+        if let Some(spawn_memory) = memory.spawns.remove(&id) {
+          info!("removing memory for destroyed spawn {id}");
+          city::spawn_removed(id, spawn_memory);
+        }
+      }
+      next
+    }
+    Stage::Sources => {
+      // Sources never go away, so one that doesn't resolve is in a room we
+      // have lost vision of and aren't using anymore.
+      let (dead, next) = dead_keys(memory.sources.keys(), start, GC_BATCH,
+                                   |id| id.resolve().is_some());
+      for id in dead {
+        if let Some(source_memory) = memory.sources.remove(&id) {
+          info!("removing memory for unseen source {id}");
+          harvester::source_removed(id, source_memory);
+        }
+      }
+      next
+    }
+  };
+  GC_CURSOR.with(|cursor| cursor.set(match next {
+    Some(start) => (stage, start),
+    None => (stage.next(), 0),
+  }));
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn batches_resume_after_removals() {
+    let keys: Vec<u32> = (0..10).collect();
+    let (dead, next) = dead_keys(keys.iter(), 0, 4, |k| k % 2 == 0);
+    assert_eq!(dead, vec![1, 3]);
+    // after removing 1 and 3, the unchecked keys start at index 2.
+    assert_eq!(next, Some(2));
+    let remaining: Vec<u32> = vec![0, 2, 4, 5, 6, 7, 8, 9];
+    let (dead, next) = dead_keys(remaining.iter(), 2, 4, |k| k % 2 == 0);
+    assert_eq!(dead, vec![5, 7]);
+    assert_eq!(next, Some(4));
+    let remaining: Vec<u32> = vec![0, 2, 4, 6, 8, 9];
+    let (dead, next) = dead_keys(remaining.iter(), 4, 4, |k| k % 2 == 0);
+    assert_eq!(dead, vec![9]);
+    assert_eq!(next, None);
+  }
+}
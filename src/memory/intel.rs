@@ -0,0 +1,133 @@
+//! What we last saw in each room, kept for rooms we can't see now.
+
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use screeps::{prelude::*, find, game, Room, RoomXY, StructureObject};
+
+use super::Memory;
+use crate::creeps::harvester::keeper_lairs;
+use crate::storage::cbor;
+
+/// How old intel on a room can get before it's worth looking again.
+pub const STALE_AFTER: u32 = 5000;
+/// How often intel on a room we can see is refreshed.
+const INTEL_REFRESH: u32 = 100;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Persist, Serialize, Deserialize)]
+pub struct RoomIntel {
+  /// The tick the room was last seen.
+  #[persist(0)] pub last_seen: u32,
+  #[persist(1, "cbor::xy_runs")]
+  pub sources: Vec<RoomXY>,
+  #[persist(2, "cbor::xy_runs")]
+  pub minerals: Vec<RoomXY>,
+  /// Who owns the controller, if anyone.
+  #[persist(3)] pub owner: Option<String>,
+  /// Who has the controller reserved, if anyone.
+  #[persist(4)] pub reserved_by: Option<String>,
+  /// The controller level, 0 when unowned or there's no controller.
+  #[persist(5)] pub level: u8,
+  #[persist(6)] pub has_controller: bool,
+  /// Hostile structures other than keeper lairs.
+  #[persist(7)] pub hostile_structures: u16,
+  #[persist(8)] pub hostile_towers: u8,
+  #[persist(9)] pub invader_core: bool,
+  #[persist(10, "cbor::xy_runs")]
+  pub keeper_lairs: Vec<RoomXY>,
+  /// Hash of the terrain, to tell whether a room's layout plans still fit.
+  #[persist(11)] pub terrain_hash: u64,
+}
+
+/// FNV-1a over the terrain bytes.
+pub fn terrain_hash(terrain: &[u8]) -> u64 {
+  terrain.iter().fold(0xcbf29ce484222325, |hash, byte| {
+    (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+  })
+}
+
+impl RoomIntel {
+  /// Look over a room we can see.
+  pub fn survey(room: &Room, time: u32, previous: Option<&RoomIntel>) -> RoomIntel {
+    let controller = room.controller();
+    let hostile_structures = room.find(find::HOSTILE_STRUCTURES, None);
+    let count = |pred: fn(&StructureObject) -> bool| {
+      hostile_structures.iter().filter(|s| pred(s)).count()
+    };
+    let hostile_count = count(|s| !matches!(s, StructureObject::StructureKeeperLair(_)));
+    let towers = count(|s| matches!(s, StructureObject::StructureTower(_)));
+    let invader_core = count(|s| matches!(s, StructureObject::StructureInvaderCore(_))) > 0;
+    let keeper_lairs = keeper_lairs(hostile_structures)
+      .map(|lair| lair.pos().xy())
+      .collect();
+    // the terrain never changes, so only hash it the first time.
+    let terrain_hash = match previous {
+      Some(intel) if intel.terrain_hash != 0 => intel.terrain_hash,
+      _ => terrain_hash(&room.get_terrain().get_raw_buffer().to_vec()),
+    };
+    RoomIntel {
+      last_seen: time,
+      sources: room.find(find::SOURCES, None).iter().map(|s| s.pos().xy()).collect(),
+      minerals: room.find(find::MINERALS, None).iter().map(|m| m.pos().xy()).collect(),
+      owner: controller.as_ref().and_then(|c| c.owner()).map(|owner| owner.username()),
+      reserved_by: controller.as_ref().and_then(|c| c.reservation()).map(|r| r.username()),
+      level: controller.as_ref().map_or(0, |c| c.level()),
+      has_controller: controller.is_some(),
+      hostile_structures: hostile_count as u16,
+      hostile_towers: towers as u8,
+      invader_core,
+      keeper_lairs,
+      terrain_hash,
+    }
+  }
+
+  pub fn is_stale(&self, time: u32) -> bool {
+    self.last_seen + STALE_AFTER <= time
+  }
+
+  /// Whether someone other than `username` owns or reserves the room.
+  pub fn is_taken(&self, username: Option<&str>) -> bool {
+    let others = |name: &Option<String>| name.is_some() && name.as_deref() != username;
+    others(&self.owner) || others(&self.reserved_by)
+  }
+}
+
+/// Record intel on every room we can see that hasn't been looked at lately.
+pub fn record_visible_rooms(memory: &mut Memory) {
+  let time = game::time();
+  for room in game::rooms().values() {
+    let previous = memory.intel.get(&room.name());
+    if previous.map_or(false, |intel| intel.last_seen + INTEL_REFRESH > time) {
+      continue
+    }
+    let intel = RoomIntel::survey(&room, time, previous);
+    memory.intel.insert(room.name(), intel);
+  }
+}
+
+/// The room among `candidates` that was seen longest ago, with rooms never
+/// seen first.
+pub fn stalest<K: Copy>(candidates: impl Iterator<Item = (K, Option<u32>)>) -> Option<K> {
+  candidates
+    .min_by_key(|(_, last_seen)| last_seen.map_or(0, |seen| seen + 1))
+    .map(|(room, _)| room)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn scouts_go_where_intel_is_oldest() {
+    assert_eq!(stalest([(1, Some(40)), (2, Some(10)), (3, Some(900))].into_iter()), Some(2));
+    assert_eq!(stalest([(1, Some(0)), (2, None)].into_iter()), Some(2));
+    assert_eq!(stalest(std::iter::empty::<(u8, Option<u32>)>()), None);
+
+    let intel = RoomIntel { last_seen: 100, reserved_by: Some("me".into()), ..Default::default() };
+    assert!(!intel.is_stale(100 + STALE_AFTER - 1));
+    assert!(intel.is_stale(100 + STALE_AFTER));
+    assert!(!intel.is_taken(Some("me")));
+    assert!(intel.is_taken(Some("you")));
+
+    assert_ne!(terrain_hash(&[0, 1, 0]), terrain_hash(&[0, 0, 1]));
+  }
+}
@@ -2,9 +2,9 @@
 // We want a local cache, a way to periodically persist important information to
 // the main memory, and a way to survey the game state to recreate important
 // cache information.
-// But that's for the future.
 //
-// For now I just want a simple serialize and deserialize tool.
+// `#[derive(Persist)]` handles the last two: only `#[persist(n)]` fields are
+// written, and `#[persist(rebuild = "fn")]` fields are surveyed on revive.
 
 use std::ops::{Deref, DerefMut};
 use std::default::{Default};
@@ -14,6 +14,8 @@ use std::fmt::Debug;
 use std::io::Write;
 
 use minicbor::{Encode, Decode, Encoder};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use js_sys::{JsString, Object, Reflect};
 use base64::{Engine as _, engine::general_purpose};
 
@@ -21,23 +23,40 @@ use screeps::raw_memory;
 use screeps::local::ObjectId;
 use screeps::objects::{Creep, StructureSpawn, Source};
 use screeps::prelude::*;
+use screeps::RoomName;
 
 use log::*;
 use super::spawn::*;
 use super::source::*;
+use super::colony::*;
+use super::settings::Settings;
+use super::intel::RoomIntel;
 use crate::creeps::{Role, RoleTag, CreepMemory};
+use crate::creeps::outcomes::CreepStats;
 use crate::storage::cbor;
+use crate::storage::serialization::request_save;
 
-#[derive(PartialEq, Debug, Encode, Decode)]
+/// The memory kept on the heap between ticks.
+///
+/// Only the `#[persist(n)]` fields are written to the segments; everything
+/// else is rebuilt after a global reset.
+#[derive(PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub struct Memory {
-  #[n(0)] pub creep_counter: u32,
-  #[n(1)] pub creeps: BTreeMap<String, CreepMemory>,
-  #[n(2)] #[cbor(with = "cbor::object_id_map")]
+  #[persist(0)] pub creep_counter: u32,
+  #[persist(1)] pub creeps: BTreeMap<String, CreepMemory>,
+  #[persist(2, "cbor::object_id_map")]
   pub spawns: HashMap<ObjectId<StructureSpawn>, SpawnMemory>,
-  #[n(3)] #[cbor(with = "cbor::object_id_map")]
+  #[persist(3, "cbor::object_id_map")]
   pub sources: HashMap<ObjectId<Source>, SourceMemory>,
+  #[persist(4)] pub colonies: Vec<ColonyMemory>,
+  #[persist(5, "cbor::room_name_map")]
+  pub intel: HashMap<RoomName, RoomIntel>,
+  #[persist(6)] pub settings: Settings,
+  /// How each living creep has done so far, see `creeps::outcomes`.
+  #[serde(default)]
+  pub creep_stats: HashMap<String, CreepStats>,
   /// Tracks the last known tick so we can tell if we need to deserialize or not.
-  #[n(4)] pub last_time: u32
+  pub last_time: u32
 }
 
 impl Memory {
@@ -53,6 +72,8 @@ impl Memory {
       return;
     }
     self.creeps.insert(name, mem);
+    // we can't work out what a creep was doing after a reset, so save now.
+    request_save();
   }
 
   pub fn creep(&self, creep: &Creep) -> Option<&CreepMemory> {
@@ -63,6 +84,20 @@ impl Memory {
     self.creeps.get_mut(name)
   }
 
+  /// The rooms that `home` is expanding into.
+  pub fn colony(&self, home: RoomName) -> Option<&ColonyMemory> {
+    self.colonies.iter().find(|colony| colony.home == home)
+  }
+
+  pub fn colony_mut(&mut self, home: RoomName) -> Option<&mut ColonyMemory> {
+    self.colonies.iter_mut().find(|colony| colony.home == home)
+  }
+
+  /// What we last saw in a room.
+  pub fn intel(&self, room: RoomName) -> Option<&RoomIntel> {
+    self.intel.get(&room)
+  }
+
   pub fn spawn_mut(
     &mut self, id: ObjectId<StructureSpawn>
   ) -> Entry<'_, ObjectId<StructureSpawn>, SpawnMemory> {
@@ -77,6 +112,10 @@ impl Default for Memory {
       creeps: BTreeMap::default(),
       spawns: HashMap::default(),
       sources: HashMap::default(),
+      colonies: Vec::default(),
+      intel: HashMap::default(),
+      settings: Settings::default(),
+      creep_stats: HashMap::default(),
       last_time: 0, // may need to avoid zero if sim starts at 0? but 1 tick delay.
     }
   }
@@ -0,0 +1,342 @@
+//! Schema versioning for the persisted memory.
+//!
+//! The encoded memory starts with the schema version it was written with,
+//! followed by the persisted half of `Memory` (see `Persist`). When a change to
+//! `Memory`, `CreepMemory`, `SpawnMemory` or anything they contain alters the
+//! encoding (a new persisted field, a renumbered `#[persist(..)]`, a removed
+//! variant) we:
+//!
+//! 1. bump `SCHEMA_VERSION`,
+//! 2. copy the old definitions of whatever changed into a `vN` module below,
+//!    along with an `impl From<vN::T> for T` (or for the `vN+1` version),
+//! 3. add an arm to `decode_from` that decodes the old layout and walks it up
+//!    the chain to the current one.
+//!
+//! That way a deploy that changes the layout keeps the live state instead of
+//! resetting to `Memory::default()`.
+
+use minicbor::{Encoder, Decoder};
+use minicbor::data::Type;
+use minicbor::{encode, decode};
+
+use log::*;
+use persist_memory::Persist;
+use super::{Memory, PersistMemory};
+
+/// Version of the layout this build writes.
+///
+/// - 1: `Memory` encoded directly.
+/// - 2: only the persisted fields, so `last_time` is no longer written.
+/// - 3: added `colonies`.
+/// - 4: added `ColonyMemory::remotes`.
+/// - 5: added `intel`.
+/// - 6: added `settings`.
+pub const SCHEMA_VERSION: u32 = 6;
+
+/// Memory written before there was a version header uses this layout.
+const UNVERSIONED: u32 = 1;
+
+/// Write the version header followed by the memory.
+pub fn encode_versioned<W: encode::Write>(
+  memory: &Memory, e: &mut Encoder<W>
+) -> Result<(), encode::Error<W::Error>> {
+  e.u32(SCHEMA_VERSION)?.encode(memory.to_persist())?;
+  Ok(())
+}
+
+/// Read the version header and decode the memory, migrating it if it was
+/// written with an older layout.
+pub fn decode_versioned(buffer: &[u8]) -> Result<Memory, decode::Error> {
+  let mut d = Decoder::new(buffer);
+  // `Memory` is encoded as an array, so anything else is a version header.
+  let version = match d.datatype()? {
+    Type::Array | Type::ArrayIndef => UNVERSIONED,
+    _ => d.u32()?,
+  };
+  if version != SCHEMA_VERSION {
+    info!("migrating memory from schema version {version} to {SCHEMA_VERSION}");
+  }
+  decode_from(version, &mut d)
+}
+
+/// Decode memory laid out as it was at `version` and upgrade it to the
+/// current layout.
+fn decode_from(version: u32, d: &mut Decoder<'_>) -> Result<Memory, decode::Error> {
+  let persisted: PersistMemory = match version {
+    // Version 1 only had the extra `last_time` field, which is skipped.
+    1 | 2 => d.decode::<v2::PersistMemory>()?.into(),
+    3 => d.decode::<v3::PersistMemory>()?.into(),
+    4 => d.decode::<v4::PersistMemory>()?.into(),
+    5 => d.decode::<v5::PersistMemory>()?.into(),
+    SCHEMA_VERSION => d.decode()?,
+    v if v > SCHEMA_VERSION => return Err(decode::Error::message(format!(
+      "memory has schema version {v} which is newer than {SCHEMA_VERSION}"))),
+    v => return Err(decode::Error::message(format!(
+      "no migration from memory schema version {v}"))),
+  };
+  Ok(Memory::revive(persisted))
+}
+
+mod v2 {
+  use std::collections::{BTreeMap, HashMap};
+  use minicbor::Decode;
+  use persist_memory::Persist;
+  use screeps::local::ObjectId;
+  use screeps::objects::{StructureSpawn, Source};
+  use crate::creeps::memory::PersistCreepMemory;
+  use crate::memory::{PersistSpawnMemory, PersistSourceMemory};
+  use crate::memory;
+  use crate::storage::cbor;
+
+  #[derive(Decode)]
+  pub struct PersistMemory {
+    #[n(0)] creep_counter: u32,
+    #[n(1)] creeps: BTreeMap<String, PersistCreepMemory>,
+    #[n(2)] #[cbor(with = "cbor::object_id_map")]
+    spawns: HashMap<ObjectId<StructureSpawn>, PersistSpawnMemory>,
+    #[n(3)] #[cbor(with = "cbor::object_id_map")]
+    sources: HashMap<ObjectId<Source>, PersistSourceMemory>,
+  }
+
+  impl From<PersistMemory> for super::PersistMemory {
+    fn from(old: PersistMemory) -> Self {
+      super::PersistMemory {
+        creep_counter: old.creep_counter,
+        creeps: old.creeps,
+        spawns: old.spawns,
+        sources: old.sources,
+        colonies: Vec::new(),
+        intel: HashMap::new(),
+        settings: memory::Settings::default().to_persist(),
+      }
+    }
+  }
+}
+
+mod v3 {
+  use std::collections::{BTreeMap, HashMap};
+  use minicbor::Decode;
+  use persist_memory::Persist;
+  use screeps::local::ObjectId;
+  use screeps::objects::{StructureSpawn, Source};
+  use crate::creeps::memory::PersistCreepMemory;
+  use crate::memory::{PersistSpawnMemory, PersistSourceMemory, PersistColonyTarget};
+  use crate::memory;
+  use crate::storage::cbor;
+
+  #[derive(Decode)]
+  pub struct PersistColonyMemory {
+    #[n(0)] #[cbor(with = "cbor::room_name")]
+    home: screeps::RoomName,
+    #[n(1)] targets: Vec<PersistColonyTarget>,
+  }
+
+  #[derive(Decode)]
+  pub struct PersistMemory {
+    #[n(0)] creep_counter: u32,
+    #[n(1)] creeps: BTreeMap<String, PersistCreepMemory>,
+    #[n(2)] #[cbor(with = "cbor::object_id_map")]
+    spawns: HashMap<ObjectId<StructureSpawn>, PersistSpawnMemory>,
+    #[n(3)] #[cbor(with = "cbor::object_id_map")]
+    sources: HashMap<ObjectId<Source>, PersistSourceMemory>,
+    #[n(4)] colonies: Vec<PersistColonyMemory>,
+  }
+
+  impl From<PersistMemory> for super::PersistMemory {
+    fn from(old: PersistMemory) -> Self {
+      super::PersistMemory {
+        creep_counter: old.creep_counter,
+        creeps: old.creeps,
+        spawns: old.spawns,
+        sources: old.sources,
+        colonies: old.colonies.into_iter()
+          .map(|colony| memory::PersistColonyMemory {
+            home: colony.home,
+            targets: colony.targets,
+            remotes: Vec::new(),
+          })
+          .collect(),
+        intel: HashMap::new(),
+        settings: memory::Settings::default().to_persist(),
+      }
+    }
+  }
+}
+
+mod v4 {
+  use std::collections::{BTreeMap, HashMap};
+  use minicbor::Decode;
+  use persist_memory::Persist;
+  use screeps::local::ObjectId;
+  use screeps::objects::{StructureSpawn, Source};
+  use crate::creeps::memory::PersistCreepMemory;
+  use crate::memory::{PersistSpawnMemory, PersistSourceMemory, PersistColonyMemory};
+  use crate::memory;
+  use crate::storage::cbor;
+
+  #[derive(Decode)]
+  pub struct PersistMemory {
+    #[n(0)] creep_counter: u32,
+    #[n(1)] creeps: BTreeMap<String, PersistCreepMemory>,
+    #[n(2)] #[cbor(with = "cbor::object_id_map")]
+    spawns: HashMap<ObjectId<StructureSpawn>, PersistSpawnMemory>,
+    #[n(3)] #[cbor(with = "cbor::object_id_map")]
+    sources: HashMap<ObjectId<Source>, PersistSourceMemory>,
+    #[n(4)] colonies: Vec<PersistColonyMemory>,
+  }
+
+  impl From<PersistMemory> for super::PersistMemory {
+    fn from(old: PersistMemory) -> Self {
+      super::PersistMemory {
+        creep_counter: old.creep_counter,
+        creeps: old.creeps,
+        spawns: old.spawns,
+        sources: old.sources,
+        colonies: old.colonies,
+        intel: HashMap::new(),
+        settings: memory::Settings::default().to_persist(),
+      }
+    }
+  }
+}
+
+mod v5 {
+  use std::collections::{BTreeMap, HashMap};
+  use minicbor::Decode;
+  use persist_memory::Persist;
+  use screeps::local::ObjectId;
+  use screeps::objects::{StructureSpawn, Source};
+  use screeps::RoomName;
+  use crate::creeps::memory::PersistCreepMemory;
+  use crate::memory::{PersistSpawnMemory, PersistSourceMemory, PersistColonyMemory};
+  use crate::memory::intel::PersistRoomIntel;
+  use crate::memory;
+  use crate::storage::cbor;
+
+  #[derive(Decode)]
+  pub struct PersistMemory {
+    #[n(0)] creep_counter: u32,
+    #[n(1)] creeps: BTreeMap<String, PersistCreepMemory>,
+    #[n(2)] #[cbor(with = "cbor::object_id_map")]
+    spawns: HashMap<ObjectId<StructureSpawn>, PersistSpawnMemory>,
+    #[n(3)] #[cbor(with = "cbor::object_id_map")]
+    sources: HashMap<ObjectId<Source>, PersistSourceMemory>,
+    #[n(4)] colonies: Vec<PersistColonyMemory>,
+    #[n(5)] #[cbor(with = "cbor::room_name_map")]
+    intel: HashMap<RoomName, PersistRoomIntel>,
+  }
+
+  impl From<PersistMemory> for super::PersistMemory {
+    fn from(old: PersistMemory) -> Self {
+      super::PersistMemory {
+        creep_counter: old.creep_counter,
+        creeps: old.creeps,
+        spawns: old.spawns,
+        sources: old.sources,
+        colonies: old.colonies,
+        intel: old.intel,
+        settings: memory::Settings::default().to_persist(),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::{BTreeMap, HashMap};
+  use screeps::local::ObjectId;
+  use screeps::objects::{StructureSpawn, Source};
+  use crate::creeps::early_worker::EarlyWorker;
+  use crate::creeps::outcomes::CreepStats;
+  use crate::creeps::memory::PersistCreepMemory;
+  use screeps::{RoomName, RoomXY};
+  use crate::memory::intel::RoomIntel;
+  use crate::memory::{SpawnMemory, PersistSpawnMemory, PersistSourceMemory};
+  use crate::memory::{ColonyMemory, ColonyTarget, Expansion};
+  use crate::storage::cbor;
+
+  const SPAWN_ID_RAW: u128 = 251504297449469618279889252367202254872;
+
+  fn sample_memory() -> Memory {
+    let mut mem = Memory::default();
+    mem.creep_counter = 3;
+    mem.creeps.insert("EarlyWorker-2".to_string(), EarlyWorker::Idle.into());
+    mem.spawns.insert(ObjectId::from_packed(SPAWN_ID_RAW), SpawnMemory {
+      initialized: true,
+    });
+    mem
+  }
+
+  #[test]
+  fn versioned_round_trip() {
+    let mut mem = sample_memory();
+    mem.colonies.push(ColonyMemory {
+      home: RoomName::new("W1N1").unwrap(),
+      targets: vec![ColonyTarget {
+        room: RoomName::new("W2N1").unwrap(),
+        expansion: Expansion::Reserve,
+      }],
+      remotes: Vec::new(),
+    });
+    mem.intel.insert(RoomName::new("W2N1").unwrap(), RoomIntel {
+      last_seen: 7,
+      sources: vec![RoomXY::try_from((10, 12)).unwrap()],
+      reserved_by: Some("Invader".to_string()),
+      ..Default::default()
+    });
+    mem.settings.save_interval = 25;
+    let mut buffer = Vec::new();
+    encode_versioned(&mem, &mut Encoder::new(&mut buffer)).expect("encode");
+    assert_eq!(decode_versioned(&buffer).expect("decode"), mem);
+  }
+
+  #[test]
+  fn heap_only_fields_are_not_written() {
+    let mem = sample_memory();
+    let mut heap = sample_memory();
+    heap.last_time = 12;
+    let mut stats = CreepStats::default();
+    stats.delivered = 50;
+    heap.creep_stats.insert("EarlyWorker-2".to_string(), stats);
+    let mut buffer = Vec::new();
+    encode_versioned(&heap, &mut Encoder::new(&mut buffer)).expect("encode");
+    assert_eq!(decode_versioned(&buffer).expect("decode"), mem);
+  }
+
+  /// How version 1 wrote `Memory`, with `last_time` as field 4.
+  #[derive(minicbor::Encode)]
+  struct V1Memory {
+    #[n(0)] creep_counter: u32,
+    #[n(1)] creeps: BTreeMap<String, PersistCreepMemory>,
+    #[n(2)] #[cbor(with = "cbor::object_id_map")]
+    spawns: HashMap<ObjectId<StructureSpawn>, PersistSpawnMemory>,
+    #[n(3)] #[cbor(with = "cbor::object_id_map")]
+    sources: HashMap<ObjectId<Source>, PersistSourceMemory>,
+    #[n(4)] last_time: u32,
+  }
+
+  #[test]
+  fn loads_unversioned_memory() {
+    let mem = sample_memory();
+    let persisted = mem.to_persist();
+    let old = V1Memory {
+      creep_counter: persisted.creep_counter,
+      creeps: persisted.creeps,
+      spawns: persisted.spawns,
+      sources: persisted.sources,
+      last_time: 40,
+    };
+    let buffer = minicbor::to_vec(&old).expect("encode");
+    assert_eq!(decode_versioned(&buffer).expect("decode"), mem);
+  }
+
+  #[test]
+  fn rejects_newer_versions() {
+    let mem = sample_memory();
+    let mut buffer = Vec::new();
+    Encoder::new(&mut buffer).u32(SCHEMA_VERSION + 1).unwrap()
+      .encode(mem.to_persist()).unwrap();
+    assert!(decode_versioned(&buffer).is_err());
+  }
+}
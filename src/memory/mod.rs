@@ -1,7 +1,15 @@
 mod main;
+mod gc;
 mod spawn;
 mod source;
+mod colony;
+mod settings;
+pub mod intel;
+pub mod migration;
 
 pub use spawn::*;
 pub use source::*;
+pub use colony::*;
+pub use settings::*;
 pub use main::*;
+pub use gc::collect_garbage;
@@ -0,0 +1,23 @@
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
+use std::default::Default;
+
+/// How many ticks the heap copy of memory goes between saves by default.
+pub const DEFAULT_SAVE_INTERVAL: u32 = 10;
+
+/// Tunables that can be changed from the console, e.g.
+/// `memorySet("settings.save_interval", "20")`.
+#[derive(PartialEq, Debug, Persist, Serialize, Deserialize)]
+pub struct Settings {
+  /// How many ticks the heap copy of memory can go without being written
+  /// back to the segments, unless something asks for it sooner.
+  #[persist(0)] pub save_interval: u32,
+}
+
+impl Default for Settings {
+  fn default() -> Settings {
+    Settings {
+      save_interval: DEFAULT_SAVE_INTERVAL,
+    }
+  }
+}
@@ -1,8 +1,9 @@
 // TODO: add a way to track the saturation of a source.
 
-use minicbor::{Encode, Decode};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use std::default::Default;
 
-#[derive(Default, Debug, PartialEq, Eq, Encode, Decode)]
+#[derive(Default, Debug, PartialEq, Eq, Persist, Serialize, Deserialize)]
 pub struct SourceMemory {
 }
@@ -1,14 +1,15 @@
-use minicbor::{Encode, Decode};
+use persist_memory::Persist;
+use serde::{Serialize, Deserialize};
 use std::default::Default;
 use crate::creeps::RoleTag;
 
-#[derive(PartialEq, Debug, Encode, Decode)]
+#[derive(PartialEq, Debug, Persist, Serialize, Deserialize)]
 pub struct SpawnMemory {
   /// Whether the room has been initialized.
   ///
   /// This includes setting up construction sites at each spawn
   /// for energy to be deposited at.
-  #[n(0)] pub initialized: bool
+  #[persist(0)] pub initialized: bool
 }
 
 impl Default for SpawnMemory {
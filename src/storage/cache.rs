@@ -1,70 +1,277 @@
 use std::collections::HashMap;
 use std::hash::Hash;
 use core::default::Default;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::fmt::Debug;
+use std::rc::Rc;
 
+use minicbor::{Encoder, Decoder, decode};
 use screeps::game;
+use screeps::RoomName;
+use screeps::local::{ObjectId, RawObjectId};
+use log::*;
+
+use super::cbor::CacheCodec;
+
+/// Something cache entries can depend on. Calling `invalidate_tag` clears
+/// every entry that was tagged with it, in every cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheTag {
+  Room(RoomName),
+  Object(RawObjectId),
+}
+
+impl From<RoomName> for CacheTag {
+  fn from(name: RoomName) -> CacheTag {
+    CacheTag::Room(name)
+  }
+}
+
+impl<T> From<ObjectId<T>> for CacheTag {
+  fn from(id: ObjectId<T>) -> CacheTag {
+    CacheTag::Object(id.into())
+  }
+}
 
 #[derive(Debug)]
 struct CacheEntry<T> {
   created: u32,
   invalidate_at: Option<u32>,
+  /// The last tick the entry was read or written, for LRU eviction.
+  last_used: u32,
+  /// See `caches_tagged`.
+  tags: Vec<CacheTag>,
   value: T,
 }
 
-/// How often do we go through and delete unused entries
-/// in the cache.
-const CLEAN_UP_PERIOD: u32 = 998;
+/// How many entries a cache holds if `mk_cache!` isn't given a capacity.
+pub const DEFAULT_CAPACITY: usize = 500;
+
+/// How many entries each cache checks for staleness per tick. The sweep
+/// walks the whole map over several ticks instead of all at once.
+const SWEEP_BATCH: usize = 20;
+
+/// Fraction of the heap limit in use at which caches shrink to half their
+/// capacity, and to a quarter at `HEAP_CRITICAL`.
+const HEAP_PRESSURED: f64 = 0.7;
+const HEAP_CRITICAL: f64 = 0.85;
+
+/// Where a cache gets the tick, CPU and heap readings from. `GameClock` reads
+/// them from the game, and tests use a `ManualClock` so caches can run off the
+/// server.
+pub trait Clock: Debug {
+  fn time(&self) -> u32;
+  fn cpu_used(&self) -> f64;
+  /// The fraction of the heap limit that is in use.
+  fn heap_usage(&self) -> f64;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct GameClock;
+
+thread_local! {
+  /// The tick and heap usage fraction it was measured at.
+  static HEAP_USAGE: Cell<(u32, f64)> = Cell::new((u32::MAX, 0.0));
+}
+
+impl Clock for GameClock {
+  fn time(&self) -> u32 {
+    game::time()
+  }
+
+  fn cpu_used(&self) -> f64 {
+    game::cpu::get_used()
+  }
+
+  /// Measured once per tick, since the heap statistics aren't free.
+  fn heap_usage(&self) -> f64 {
+    let time = game::time();
+    HEAP_USAGE.with(|usage| {
+      let (measured_at, fraction) = usage.get();
+      if measured_at == time {
+        return fraction
+      }
+      let heap = game::cpu::get_heap_statistics();
+      let used = heap.used_heap_size() as f64 + heap.externally_allocated_size() as f64;
+      let fraction = used / heap.heap_size_limit().max(1) as f64;
+      usage.set((time, fraction));
+      fraction
+    })
+  }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock {
+  time: Cell<u32>,
+  cpu: Cell<f64>,
+  heap: Cell<f64>,
+}
+
+impl ManualClock {
+  pub fn starting_at(time: u32) -> Rc<ManualClock> {
+    Rc::new(ManualClock { time: Cell::new(time), ..ManualClock::default() })
+  }
+
+  pub fn advance(&self, ticks: u32) {
+    self.time.set(self.time.get() + ticks);
+  }
+
+  /// Pretend `cpu` more CPU has been used this tick.
+  pub fn use_cpu(&self, cpu: f64) {
+    self.cpu.set(self.cpu.get() + cpu);
+  }
+
+  pub fn set_heap_usage(&self, fraction: f64) {
+    self.heap.set(fraction);
+  }
+}
+
+impl Clock for ManualClock {
+  fn time(&self) -> u32 {
+    self.time.get()
+  }
+
+  fn cpu_used(&self) -> f64 {
+    self.cpu.get()
+  }
+
+  fn heap_usage(&self) -> f64 {
+    self.heap.get()
+  }
+}
+
+/// The capacity a cache should keep to given how much of the heap is in use.
+fn effective_capacity(capacity: usize, heap_usage: f64) -> usize {
+  let capacity = if heap_usage >= HEAP_CRITICAL {
+    capacity / 4
+  } else if heap_usage >= HEAP_PRESSURED {
+    capacity / 2
+  } else {
+    capacity
+  };
+  capacity.max(1)
+}
+
+/// Counters for how well a cache is doing since the last global reset.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+  pub hits: u32,
+  /// Every time the value had to be calculated.
+  pub misses: u32,
+  /// Misses where there was an entry but it had outlived the cache lifetime.
+  pub expired: u32,
+  /// Calls to `invalidate_now` and `invalidate_next_tick` that hit an entry.
+  pub invalidations: u32,
+  /// CPU spent calculating values, including any other caches they used.
+  pub recompute_cpu: f64,
+  /// Entries dropped to stay under capacity.
+  pub evictions: u32,
+}
 
 #[derive(Debug)]
 struct InternalCache<I, T, const D: u32> {
-  /// Has this been cleaned up this tick already.
-  has_been_cleaned: bool,
-  map: HashMap<I, CacheEntry<T>>
+  /// The last tick part of the map was swept for stale entries.
+  last_swept: u32,
+  /// Where in the map's iteration order the next sweep starts.
+  sweep_cursor: usize,
+  /// Most entries to keep before evicting the least recently used.
+  capacity: usize,
+  map: HashMap<I, CacheEntry<T>>,
+  stats: CacheStats,
 }
 
 impl<I, T, const D: u32> Default for InternalCache<I,T,D> {
   fn default() -> Self {
     InternalCache {
-      has_been_cleaned: true,
-      map: HashMap::default()
+      last_swept: 0,
+      sweep_cursor: 0,
+      capacity: DEFAULT_CAPACITY,
+      map: HashMap::default(),
+      stats: CacheStats::default(),
     }
   }
 }
 
-#[repr(transparent)]
 #[derive(Debug)]
 pub struct ThreadLocalCache<K, T, const D: u32> {
-  cell: RefCell<InternalCache<K, T, D>>
+  cell: RefCell<InternalCache<K, T, D>>,
+  clock: Rc<dyn Clock>,
 }
 
 impl<K, T, const D: u32> Default for ThreadLocalCache<K,T,D> {
   fn default() -> Self {
     ThreadLocalCache {
-      cell: RefCell::new(InternalCache::default())
+      cell: RefCell::new(InternalCache::default()),
+      clock: Rc::new(GameClock),
+    }
+  }
+}
+
+impl<K, T, const D: u32> ThreadLocalCache<K,T,D> {
+  pub fn with_capacity(capacity: usize) -> Self {
+    ThreadLocalCache {
+      cell: RefCell::new(InternalCache { capacity, ..InternalCache::default() }),
+      ..ThreadLocalCache::default()
     }
   }
+
+  /// Use `clock` instead of the game for the time, CPU and heap usage.
+  pub fn with_clock(self, clock: Rc<dyn Clock>) -> Self {
+    ThreadLocalCache { clock, ..self }
+  }
+
+  /// The current tick according to the cache's clock.
+  pub fn now(&self) -> u32 {
+    self.clock.time()
+  }
 }
 
 impl<K: Eq + Hash + Clone, T: Clone, const D: u32> ThreadLocalCache<K,T,D> {
-  /// Run the clean up if needed. Makes sure it only runs the clean up
-  /// once.
+  /// Sweep the next `SWEEP_BATCH` entries for stale ones. Only runs once a
+  /// tick.
   #[inline]
   fn clean_up(&self, time: u32) {
     let mut cache = self.cell.borrow_mut();
-    if time % CLEAN_UP_PERIOD == 0 {
-      if !cache.has_been_cleaned {
-        cache.has_been_cleaned = true;
-        cache.map.retain(|k, v| {
-          // we add 20 just to make sure we aren't accidentally removing
-          // anything with tight timing, or a cache that has a lifetime of
-          // zero.
-          v.created + D + 20 >= time
-        });
-      }
-    } else {
-      cache.has_been_cleaned = false;
+    if cache.last_swept == time {
+      return
+    }
+    cache.last_swept = time;
+    let len = cache.map.len();
+    if len == 0 {
+      return
+    }
+    let start = if cache.sweep_cursor >= len { 0 } else { cache.sweep_cursor };
+    cache.sweep_cursor = start + SWEEP_BATCH;
+    let stale: Vec<K> = cache.map.iter()
+      .skip(start)
+      .take(SWEEP_BATCH)
+      // we add 20 just to make sure we aren't accidentally removing
+      // anything with tight timing, or a cache that has a lifetime of
+      // zero.
+      .filter(|(_, v)| v.created + D + 20 < time)
+      .map(|(k, _)| k.clone())
+      .collect();
+    for key in stale.iter() {
+      cache.map.remove(key);
+    }
+  }
+
+  /// Drop the least recently used entries until there's room for one more
+  /// under `capacity`.
+  fn evict(cache: &mut InternalCache<K, T, D>, capacity: usize) {
+    if cache.map.len() < capacity {
+      return
+    }
+    let mut by_use: Vec<(u32, K)> = cache.map.iter()
+      .map(|(k, v)| (v.last_used, k.clone()))
+      .collect();
+    by_use.sort_unstable_by_key(|(last_used, _)| *last_used);
+    let excess = cache.map.len() + 1 - capacity;
+    for (_, key) in by_use.into_iter().take(excess) {
+      cache.map.remove(&key);
     }
+    cache.stats.evictions += excess as u32;
   }
 
   /// This accesses the cached value if it is present and up to date.
@@ -74,24 +281,53 @@ impl<K: Eq + Hash + Clone, T: Clone, const D: u32> ThreadLocalCache<K,T,D> {
     key: &K,
     calc: impl FnOnce(Option<T>) -> T
   ) -> T {
-    let time = game::time();
+    self.caches_tagged(key, &[], calc)
+  }
+
+  /// Like `caches`, but if the value is recalculated the entry is tagged with
+  /// `tags` so `invalidate_tag` can clear it.
+  pub fn caches_tagged(
+    &self,
+    key: &K,
+    tags: &[CacheTag],
+    calc: impl FnOnce(Option<T>) -> T
+  ) -> T {
+    let time = self.clock.time();
     self.clean_up(time);
     let cache = self.cell.borrow();
     match cache.map.get(key) {
       Some(entry) if entry.created + D >= time
           && entry.invalidate_at.map_or(true, |t| t > time) => {
-        entry.value.clone()
+        let val = entry.value.clone();
+        drop(cache);
+        let mut cache = self.cell.borrow_mut();
+        cache.stats.hits += 1;
+        if let Some(entry) = cache.map.get_mut(key) {
+          entry.last_used = time;
+        }
+        val
       }
       _ => {
-        // we have to drop index so that if calc uses
-        // a different cache it all works out.
         drop(cache);
-        let mut cache = self.cell.borrow_mut();
-        let old_value = cache.map.remove(key);
+        let old_value = self.cell.borrow_mut().map.remove(key);
+        let expired = old_value.as_ref().map_or(false, |v| v.created + D < time);
+        let start_cpu = self.clock.cpu_used();
+        // nothing can be borrowed while calc runs, since it may read this
+        // cache or invalidate tags across every cache.
         let val = calc(old_value.map(|v| v.value));
+        let mut cache = self.cell.borrow_mut();
+        cache.stats.recompute_cpu += self.clock.cpu_used() - start_cpu;
+        cache.stats.misses += 1;
+        if expired {
+          cache.stats.expired += 1;
+        }
+        let capacity = effective_capacity(cache.capacity, self.clock.heap_usage());
+        Self::evict(&mut cache, capacity);
         let entry = CacheEntry {
           created: time,
           invalidate_at: None,
+          last_used: time,
+          tags: tags.to_vec(),
           value: val.clone()
         };
         cache.map.insert(key.clone(), entry);
@@ -102,29 +338,339 @@ impl<K: Eq + Hash + Clone, T: Clone, const D: u32> ThreadLocalCache<K,T,D> {
 
   pub fn invalidate_now(&self, key: &K) {
     let mut cache = self.cell.borrow_mut();
-    cache.map.remove(key);
+    if cache.map.remove(key).is_some() {
+      cache.stats.invalidations += 1;
+    }
   }
 
   /// Tell the cache to update next tick.
   pub fn invalidate_next_tick(&self, key: &K) {
     let mut cache = self.cell.borrow_mut();
     if let Some(entry) = cache.map.get_mut(key) {
-      entry.invalidate_at = Some(game::time() + 1);
+      entry.invalidate_at = Some(self.clock.time() + 1);
+      cache.stats.invalidations += 1;
     }
   }
+
+  /// Remove every entry tagged with `tag`, returning how many there were.
+  pub fn invalidate_tag(&self, tag: &CacheTag) -> usize {
+    let mut cache = self.cell.borrow_mut();
+    let before = cache.map.len();
+    cache.map.retain(|_, entry| !entry.tags.contains(tag));
+    let removed = before - cache.map.len();
+    cache.stats.invalidations += removed as u32;
+    removed
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    self.cell.borrow().stats
+  }
+
+  /// How many entries are in the cache, including expired ones that haven't
+  /// been cleaned up yet.
+  pub fn len(&self) -> usize {
+    self.cell.borrow().map.len()
+  }
+}
+
+impl<K, T, const D: u32> ThreadLocalCache<K,T,D>
+where K: Eq + Hash + Clone + CacheCodec, T: Clone + CacheCodec {
+  /// Encode the entries that haven't expired as `[created, key, value]` arrays.
+  pub fn snapshot(&self, time: u32) -> Vec<u8> {
+    let cache = self.cell.borrow();
+    let live: Vec<_> = cache.map.iter()
+      .filter(|(_, entry)| entry.created + D >= time)
+      .collect();
+    let mut buffer = Vec::new();
+    let mut e = Encoder::new(&mut buffer);
+    e.array(live.len() as u64).expect("encoding to a vec is infallible");
+    for (key, entry) in live {
+      e.array(3).and_then(|e| e.u32(entry.created)).expect("encoding to a vec is infallible");
+      key.encode_to(&mut e).expect("encoding to a vec is infallible");
+      entry.value.encode_to(&mut e).expect("encoding to a vec is infallible");
+    }
+    buffer
+  }
+
+  /// Add the entries from a `snapshot` that are still fresh, keeping any
+  /// entries that were already calculated this global.
+  pub fn restore(&self, bytes: &[u8], time: u32) -> Result<(), decode::Error> {
+    let mut d = Decoder::new(bytes);
+    let len = d.array()?
+      .ok_or(decode::Error::message("cache snapshot did not have set length"))?;
+    let mut cache = self.cell.borrow_mut();
+    for _ in 0..len {
+      if d.array()? != Some(3) {
+        return Err(decode::Error::message("cache entry was not an array of three members"));
+      }
+      let created = d.u32()?;
+      let key = K::decode_from(&mut d)?;
+      let value = T::decode_from(&mut d)?;
+      if created + D >= time && !cache.map.contains_key(&key) && cache.map.len() < cache.capacity {
+        // tags aren't persisted, so restored entries can only expire.
+        cache.map.insert(key, CacheEntry {
+          created, invalidate_at: None, last_used: created, tags: Vec::new(), value
+        });
+      }
+    }
+    Ok(())
+  }
+}
+
+/// What every cache made with `mk_cache!` registers when it is first used,
+/// so we can list them all from the console.
+#[derive(Clone, Copy)]
+pub struct RegisteredCache {
+  pub name: &'static str,
+  pub lifetime: u32,
+  /// The stats and number of entries.
+  pub stats: fn() -> (CacheStats, usize),
+  pub invalidate_tag: fn(&CacheTag) -> usize,
+}
+
+thread_local! {
+  static REGISTRY: RefCell<Vec<RegisteredCache>> = RefCell::new(Vec::new());
+}
+
+pub fn register(cache: RegisteredCache) {
+  REGISTRY.with(|registry| registry.borrow_mut().push(cache));
+}
+
+/// Clear the entries tagged with `tag` in every cache, returning how many
+/// were removed.
+pub fn invalidate_tag(tag: impl Into<CacheTag>) -> usize {
+  let tag = tag.into();
+  let caches = REGISTRY.with(|registry| registry.borrow().clone());
+  let removed = caches.iter().map(|cache| (cache.invalidate_tag)(&tag)).sum();
+  if removed > 0 {
+    debug!("invalidated {removed} cache entries tagged {tag:?}");
+  }
+  removed
+}
+
+/// A table of the stats for every cache that has been used since the global
+/// reset.
+pub fn stats_table() -> String {
+  let caches = REGISTRY.with(|registry| registry.borrow().clone());
+  let mut rows: Vec<_> = caches.iter()
+    .map(|cache| {
+      let (stats, len) = (cache.stats)();
+      (cache.name, cache.lifetime, len, stats)
+    })
+    .collect();
+  rows.sort_by_key(|row| row.0);
+  format_stats(&rows)
+}
+
+fn format_stats(rows: &[(&str, u32, usize, CacheStats)]) -> String {
+  let mut out = format!(
+    "{:<28} {:>6} {:>7} {:>8} {:>7} {:>7} {:>7} {:>7} {:>6} {:>8} {:>8}\n",
+    "cache", "life", "entries", "hits", "misses", "expired", "invalid", "evicted", "hit%", "cpu",
+    "cpu/miss");
+  for (name, lifetime, len, stats) in rows {
+    let lookups = stats.hits + stats.misses;
+    let hit_rate = if lookups == 0 { 0.0 } else { 100.0 * stats.hits as f64 / lookups as f64 };
+    let per_miss = if stats.misses == 0 { 0.0 } else { stats.recompute_cpu / stats.misses as f64 };
+    out.push_str(&format!(
+      "{:<28} {:>6} {:>7} {:>8} {:>7} {:>7} {:>7} {:>7} {:>6.1} {:>8.2} {:>8.3}\n",
+      name, lifetime, len, stats.hits, stats.misses, stats.expired, stats.invalidations,
+      stats.evictions, hit_rate, stats.recompute_cpu, per_miss));
+  }
+  out
+}
+
+/// How often the persisted caches are written to their segment.
+pub const PERSIST_INTERVAL: u32 = 100;
+
+/// What a cache made with `mk_cache! { persist ... }` registers so its
+/// entries can be written to and read back from the cache segment.
+#[derive(Clone, Copy)]
+pub struct PersistedCache {
+  /// The name of the cache module. These need to be unique.
+  pub name: &'static str,
+  pub snapshot: fn() -> Vec<u8>,
+  pub restore: fn(&[u8]),
+}
+
+#[derive(Default)]
+struct PersistedRegistry {
+  /// Whether the cache segment has been read since the global reset.
+  restored: bool,
+  caches: Vec<PersistedCache>,
+  /// Snapshots for caches that haven't been used since the global reset. We
+  /// hand them over when the cache is first used, and otherwise write them
+  /// back as they are so they aren't lost.
+  unclaimed: HashMap<String, Vec<u8>>,
+}
+
+thread_local! {
+  static PERSISTED: RefCell<PersistedRegistry> = RefCell::new(PersistedRegistry::default());
+}
+
+/// Register a persisted cache the first time it is used, getting back its
+/// snapshot if the cache segment has already been read.
+pub fn register_persisted(cache: PersistedCache) -> Option<Vec<u8>> {
+  PERSISTED.with(|registry| {
+    let mut registry = registry.borrow_mut();
+    registry.caches.push(cache);
+    registry.unclaimed.remove(cache.name)
+  })
+}
+
+/// Encode all of the persisted caches as an array of `[name, snapshot]` pairs.
+pub fn snapshot_persisted() -> Vec<u8> {
+  let (caches, unclaimed) = PERSISTED.with(|registry| {
+    let registry = registry.borrow();
+    (registry.caches.clone(), registry.unclaimed.clone())
+  });
+  let mut buffer = Vec::new();
+  let mut e = Encoder::new(&mut buffer);
+  e.array((caches.len() + unclaimed.len()) as u64).expect("encoding to a vec is infallible");
+  for cache in caches {
+    e.array(2).and_then(|e| e.str(cache.name)).and_then(|e| e.bytes(&(cache.snapshot)()))
+      .expect("encoding to a vec is infallible");
+  }
+  for (name, bytes) in unclaimed.iter() {
+    e.array(2).and_then(|e| e.str(name)).and_then(|e| e.bytes(bytes))
+      .expect("encoding to a vec is infallible");
+  }
+  buffer
+}
+
+fn decode_snapshots(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>, decode::Error> {
+  let mut d = Decoder::new(bytes);
+  let len = d.array()?
+    .ok_or(decode::Error::message("cache snapshots did not have set length"))?;
+  let mut snapshots = HashMap::with_capacity(len as usize);
+  for _ in 0..len {
+    if d.array()? != Some(2) {
+      return Err(decode::Error::message("cache snapshot was not a name and bytes"));
+    }
+    let name = d.str()?.to_string();
+    snapshots.insert(name, d.bytes()?.to_vec());
+  }
+  Ok(snapshots)
+}
+
+/// Restore the persisted caches from the output of `snapshot_persisted`.
+///
+/// Only does anything the first time it's called after a global reset.
+pub fn restore_persisted(bytes: &[u8]) {
+  let already_restored = PERSISTED.with(|registry| {
+    std::mem::replace(&mut registry.borrow_mut().restored, true)
+  });
+  if already_restored {
+    return
+  }
+  let mut snapshots = match decode_snapshots(bytes) {
+    Ok(snapshots) => snapshots,
+    Err(err) => {
+      warn!("could not decode persisted caches: {err:?}");
+      return
+    }
+  };
+  info!("restoring {} persisted caches", snapshots.len());
+  // caches that were used before the segment was read get their entries now.
+  let caches = PERSISTED.with(|registry| registry.borrow().caches.clone());
+  for cache in caches {
+    if let Some(bytes) = snapshots.remove(cache.name) {
+      (cache.restore)(&bytes);
+    }
+  }
+  PERSISTED.with(|registry| registry.borrow_mut().unclaimed = snapshots);
+}
+
+/// Mark the cache segment as read when there was nothing in it.
+pub fn nothing_to_restore() {
+  PERSISTED.with(|registry| registry.borrow_mut().restored = true);
 }
 
 #[macro_export]
 macro_rules! mk_cache {
-  ($n:ident lifetime $dur:literal by $ity:ty => $t:ty) => {
+  (persist $n:ident lifetime $dur:literal $(capacity $cap:literal)? by $ity:ty => $t:ty) => {
+    mod $n {
+      use std::hash::Hash;
+      use log::*;
+      use crate::storage::cache::{self, ThreadLocalCache, PersistedCache};
+      use super::*;
+
+      const PERSISTED: PersistedCache = PersistedCache {
+        name: stringify!($n),
+        snapshot,
+        restore,
+      };
+
+      thread_local! {
+        static CACHE: ThreadLocalCache<$ity, $t, $dur> = {
+          $crate::mk_cache!(@register $n $dur);
+          let local_cache = ThreadLocalCache::with_capacity($crate::mk_cache!(@capacity $($cap)?));
+          if let Some(bytes) = cache::register_persisted(PERSISTED) {
+            restore_into(&local_cache, &bytes);
+          }
+          local_cache
+        };
+      }
+
+      fn restore_into(local_cache: &ThreadLocalCache<$ity, $t, $dur>, bytes: &[u8]) {
+        if let Err(err) = local_cache.restore(bytes, local_cache.now()) {
+          warn!("could not restore cache {}: {err:?}", stringify!($n));
+        }
+      }
+
+      fn snapshot() -> Vec<u8> {
+        CACHE.with(|local_cache| local_cache.snapshot(local_cache.now()))
+      }
+
+      fn restore(bytes: &[u8]) {
+        CACHE.with(|local_cache| restore_into(local_cache, bytes))
+      }
+
+      $crate::mk_cache!(@access $ity => $t);
+    }
+  };
+  ($n:ident lifetime $dur:literal $(capacity $cap:literal)? by $ity:ty => $t:ty) => {
     mod $n {
       use std::hash::Hash;
       use crate::storage::cache::ThreadLocalCache;
       use super::*;
 
       thread_local! {
-        static CACHE: ThreadLocalCache<$ity, $t, $dur> = ThreadLocalCache::default();
+        static CACHE: ThreadLocalCache<$ity, $t, $dur> = {
+          $crate::mk_cache!(@register $n $dur);
+          ThreadLocalCache::with_capacity($crate::mk_cache!(@capacity $($cap)?))
+        };
       }
+
+      $crate::mk_cache!(@access $ity => $t);
+    }
+  };
+  (@capacity) => { $crate::storage::cache::DEFAULT_CAPACITY };
+  (@capacity $cap:literal) => { $cap };
+  (@register $n:ident $dur:literal) => {
+    $crate::storage::cache::register($crate::storage::cache::RegisteredCache {
+      name: stringify!($n),
+      lifetime: $dur,
+      stats,
+      invalidate_tag,
+    })
+  };
+  (@access $ity:ty => $t:ty) => {
+      fn stats() -> ($crate::storage::cache::CacheStats, usize) {
+        CACHE.with(|local_cache| (local_cache.stats(), local_cache.len()))
+      }
+      fn invalidate_tag(tag: &$crate::storage::cache::CacheTag) -> usize {
+        CACHE.with(|local_cache| local_cache.invalidate_tag(tag))
+      }
+      pub fn caches_tagged(
+        key: & $ity,
+        tags: &[$crate::storage::cache::CacheTag],
+        calc: impl FnOnce(Option<$t>) -> $t
+      ) -> $t {
+        CACHE.with(|local_cache| {
+          local_cache.caches_tagged(key, tags, calc)
+        })
+      }
+
       pub fn caches(
         key: & $ity,
         calc: impl FnOnce(Option<$t>) -> $t
@@ -143,6 +689,208 @@ macro_rules! mk_cache {
           local_cache.invalidate_next_tick(key)
         })
       }
-    }
   };
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn stats_table_lines_up() {
+    let stats = CacheStats {
+      hits: 30, misses: 10, expired: 4, invalidations: 2, recompute_cpu: 1.5, evictions: 0,
+    };
+    let table = format_stats(&[("source_harvest_spots", 150, 2, stats)]);
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].len(), lines[1].len());
+    assert!(lines[1].contains(" 75.0 "));
+    assert!(lines[1].ends_with("0.150"));
+  }
+
+  fn entry(created: u32, last_used: u32) -> CacheEntry<u32> {
+    CacheEntry { created, invalidate_at: None, last_used, tags: Vec::new(), value: 0 }
+  }
+
+  #[test]
+  fn evicts_least_recently_used() {
+    let cache: ThreadLocalCache<u32, u32, 100> = ThreadLocalCache::with_capacity(3);
+    let mut inner = cache.cell.borrow_mut();
+    inner.map.insert(1, entry(10, 40));
+    inner.map.insert(2, entry(10, 20));
+    inner.map.insert(3, entry(10, 30));
+    ThreadLocalCache::evict(&mut inner, 3);
+    assert!(!inner.map.contains_key(&2));
+    // heap pressure shrinks the capacity further.
+    ThreadLocalCache::evict(&mut inner, effective_capacity(3, HEAP_CRITICAL));
+    assert!(inner.map.is_empty());
+    assert_eq!(inner.stats.evictions, 3);
+  }
+
+  #[test]
+  fn invalidates_by_tag() {
+    let room = CacheTag::Room(RoomName::new("W1N1").unwrap());
+    let other = CacheTag::Room(RoomName::new("W2N1").unwrap());
+    let cache: ThreadLocalCache<u32, u32, 100> = ThreadLocalCache::default();
+    {
+      let mut inner = cache.cell.borrow_mut();
+      inner.map.insert(1, CacheEntry { tags: vec![room], ..entry(0, 0) });
+      inner.map.insert(2, CacheEntry { tags: vec![other, room], ..entry(0, 0) });
+      inner.map.insert(3, CacheEntry { tags: vec![other], ..entry(0, 0) });
+    }
+    assert_eq!(cache.invalidate_tag(&room), 2);
+    assert_eq!(cache.len(), 1);
+    assert_eq!(cache.stats().invalidations, 2);
+  }
+
+  thread_local! {
+    static NESTED: ThreadLocalCache<u32, u32, 100> =
+      ThreadLocalCache::default().with_clock(ManualClock::starting_at(100));
+  }
+
+  #[test]
+  fn calc_can_use_the_caches() {
+    let room = CacheTag::Room(RoomName::new("W1N1").unwrap());
+    register(RegisteredCache {
+      name: "nested",
+      lifetime: 100,
+      stats: || NESTED.with(|cache| (cache.stats(), cache.len())),
+      invalidate_tag: |tag| NESTED.with(|cache| cache.invalidate_tag(tag)),
+    });
+    NESTED.with(|cache| {
+      cache.caches_tagged(&2, &[room], |_| 20);
+      let val = cache.caches(&1, |_| {
+        // reads this cache and clears a tag everywhere mid-calculation.
+        let other = cache.caches(&3, |_| 30);
+        assert_eq!(invalidate_tag(room), 1);
+        other + 1
+      });
+      assert_eq!(val, 31);
+      assert_eq!(cache.len(), 2);
+      assert_eq!(cache.caches(&2, |_| 21), 21);
+    });
+  }
+
+  #[test]
+  fn sweep_is_spread_across_ticks() {
+    let cache: ThreadLocalCache<u32, u32, 10> = ThreadLocalCache::default();
+    for key in 0..(SWEEP_BATCH as u32 * 2) {
+      cache.cell.borrow_mut().map.insert(key, entry(0, 0));
+    }
+    cache.clean_up(100);
+    assert_eq!(cache.len(), SWEEP_BATCH);
+    // only one sweep per tick.
+    cache.clean_up(100);
+    assert_eq!(cache.len(), SWEEP_BATCH);
+    cache.clean_up(101);
+    assert_eq!(cache.len(), 0);
+  }
+
+  fn clocked<T, const D: u32>(time: u32) -> (Rc<ManualClock>, ThreadLocalCache<u32, T, D>) {
+    let clock = ManualClock::starting_at(time);
+    let cache = ThreadLocalCache::default().with_clock(clock.clone());
+    (clock, cache)
+  }
+
+  #[test]
+  fn entries_live_for_the_lifetime() {
+    let (clock, cache) = clocked::<u32, 10>(100);
+    assert_eq!(cache.caches(&1, |_| 5), 5);
+    clock.advance(10);
+    assert_eq!(cache.caches(&1, |_| 6), 5);
+    clock.advance(1);
+    assert_eq!(cache.caches(&1, |old| old.unwrap() + 1), 6);
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses, stats.expired), (1, 2, 1));
+  }
+
+  #[test]
+  fn invalidate_next_tick_keeps_value_this_tick() {
+    let (clock, cache) = clocked::<u32, 100>(100);
+    cache.caches(&1, |_| 5);
+    cache.invalidate_next_tick(&1);
+    assert_eq!(cache.caches(&1, |_| 6), 5);
+    clock.advance(1);
+    assert_eq!(cache.caches(&1, |_| 6), 6);
+    // the new entry isn't invalidated again.
+    clock.advance(1);
+    assert_eq!(cache.caches(&1, |_| 7), 6);
+    assert_eq!(cache.stats().invalidations, 1);
+
+    cache.invalidate_now(&1);
+    assert_eq!(cache.caches(&1, |_| 8), 8);
+  }
+
+  #[test]
+  fn sweep_removes_stale_entries_through_caches() {
+    let (clock, cache) = clocked::<u32, 10>(100);
+    for key in 0..(SWEEP_BATCH as u32) {
+      cache.caches(&key, |_| key);
+    }
+    // entries are kept for 20 ticks past their lifetime before sweeping.
+    clock.advance(30);
+    cache.caches(&1000, |_| 0);
+    assert_eq!(cache.len(), SWEEP_BATCH + 1);
+    // the sweep carries on from the last batch, then wraps around to the rest.
+    clock.advance(1);
+    cache.caches(&1000, |_| 0);
+    cache.caches(&2000, |_| 0);
+    let remaining = cache.len();
+    clock.advance(1);
+    cache.caches(&1000, |_| 0);
+    assert!(cache.len() < remaining);
+  }
+
+  #[test]
+  fn recompute_cpu_and_heap_pressure_use_the_clock() {
+    let clock = ManualClock::starting_at(0);
+    let cache: ThreadLocalCache<u32, u32, 100> =
+      ThreadLocalCache::with_capacity(8).with_clock(clock.clone());
+    cache.caches(&0, |_| {
+      clock.use_cpu(0.5);
+      0
+    });
+    assert_eq!(cache.stats().recompute_cpu, 0.5);
+    clock.set_heap_usage(HEAP_CRITICAL);
+    for key in 1..4 {
+      cache.caches(&key, |_| key);
+    }
+    assert_eq!(cache.len(), 2);
+  }
+
+  #[test]
+  fn snapshot_round_trip() {
+    let cache: ThreadLocalCache<u32, Option<u8>, 100> = ThreadLocalCache::default();
+    {
+      let mut inner = cache.cell.borrow_mut();
+      inner.map.insert(1, CacheEntry { created: 50, invalidate_at: None, last_used: 50, tags: Vec::new(), value: Some(3) });
+      inner.map.insert(2, CacheEntry { created: 10, invalidate_at: None, last_used: 10, tags: Vec::new(), value: None });
+    }
+    let bytes = cache.snapshot(120);
+
+    let restored: ThreadLocalCache<u32, Option<u8>, 100> = ThreadLocalCache::default();
+    restored.cell.borrow_mut().map
+      .insert(1, CacheEntry { created: 130, invalidate_at: None, last_used: 130, tags: Vec::new(), value: Some(9) });
+    restored.restore(&bytes, 140).unwrap();
+    let inner = restored.cell.borrow();
+    // the entry from this global wins, and the expired one was never written.
+    assert_eq!(inner.map.len(), 1);
+    assert_eq!(inner.map[&1].value, Some(9));
+  }
+
+  #[test]
+  fn restore_drops_expired_entries() {
+    let cache: ThreadLocalCache<u32, u32, 100> = ThreadLocalCache::default();
+    cache.cell.borrow_mut().map
+      .insert(7, CacheEntry { created: 50, invalidate_at: None, last_used: 50, tags: Vec::new(), value: 8 });
+    let bytes = cache.snapshot(60);
+
+    let fresh: ThreadLocalCache<u32, u32, 100> = ThreadLocalCache::default();
+    fresh.restore(&bytes, 100).unwrap();
+    assert_eq!(fresh.cell.borrow().map[&7].created, 50);
+    let stale: ThreadLocalCache<u32, u32, 100> = ThreadLocalCache::default();
+    stale.restore(&bytes, 151).unwrap();
+    assert!(stale.cell.borrow().map.is_empty());
+  }
+}
@@ -1,11 +1,15 @@
 //! Module with inline submodules for use with #[cbor(with = "<path>")].
 
 use std::collections::HashMap;
-use screeps::local::ObjectId;
+use screeps::local::{ObjectId, LocalCostMatrix};
+use screeps::{Position, RoomName, RoomXY};
 use minicbor::{Encode, Decode, Encoder, Decoder};
 use minicbor::encode::{Write};
 use minicbor::encode;
 use minicbor::decode;
+use screeps::RoomCoordinate;
+use crate::rooms::tile_slice::TileMap;
+use crate::util::xy::{ROOM_SIZE, ROOM_AREA, linear_index_to_xy, xy_to_linear_index};
 
 pub mod object_id {
   use super::*;
@@ -61,3 +65,396 @@ pub mod object_id_map {
     Ok(())
   }
 }
+
+/// Position within the world, stored as its packed u32.
+pub mod position {
+  use super::*;
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<Position, decode::Error> {
+    let packed = d.u32()?;
+    if (packed >> 8 & 0xFF) >= ROOM_SIZE as u32 || (packed & 0xFF) >= ROOM_SIZE as u32 {
+      return Err(decode::Error::message("packed position out of bounds"));
+    }
+    Ok(Position::from_packed(packed))
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &Position, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.u32(v.packed_repr())?;
+    Ok(())
+  }
+}
+
+/// Room name stored as a u16, using the same packing as the game.
+pub mod room_name {
+  use super::*;
+
+  /// Coordinates are shifted by half the world size to make them positive.
+  const HALF_WORLD_SIZE: i32 = 128;
+
+  /// Same as the top 16 bits of a packed position.
+  fn to_packed(name: RoomName) -> u16 {
+    let room_x = (name.x_coord() + HALF_WORLD_SIZE) as u16;
+    let room_y = (name.y_coord() + HALF_WORLD_SIZE) as u16;
+    (room_x << 8) | room_y
+  }
+
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<RoomName, decode::Error> {
+    let packed = d.u16()?;
+    Ok(Position::from_packed((packed as u32) << 16).room_name())
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &RoomName, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.u16(to_packed(*v))?;
+    Ok(())
+  }
+}
+
+/// Map keyed by room name, stored as `[room, value]` pairs.
+pub mod room_name_map {
+  use super::*;
+  pub fn decode<'b, Ctx, M: 'b>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<HashMap<RoomName, M>, decode::Error> where M: Decode<'b, Ctx> {
+    let size = d.array()?
+      .ok_or(decode::Error::message("room name map did not have set length"))?;
+    let mut map: HashMap<RoomName, M> = HashMap::with_capacity(size as usize);
+    for _ in 0..size {
+      let len = d.array()?;
+      if len != Some(2) {
+        return Err(decode::Error::message("member pair for room_name_map was not an array of two members"));
+      }
+      let name = room_name::decode(d, ctx)?;
+      let mem = M::decode(d, ctx)?;
+      map.insert(name, mem);
+    }
+    Ok(map)
+  }
+
+  pub fn encode<Ctx, M: Encode<Ctx>, W: encode::Write>(
+    map: &HashMap<RoomName, M>, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.array(map.len() as u64)?;
+    for (name, mem) in map {
+      e.array(2)?;
+      room_name::encode(name, e, ctx)?;
+      mem.encode(e, ctx)?;
+    }
+    Ok(())
+  }
+}
+
+fn xy_from_index(index: usize) -> Result<RoomXY, decode::Error> {
+  if index < ROOM_AREA {
+    Ok(linear_index_to_xy(index))
+  } else {
+    Err(decode::Error::message("room tile index out of bounds"))
+  }
+}
+
+/// Room coordinates stored as their u16 linear index.
+pub mod room_xy {
+  use super::*;
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<RoomXY, decode::Error> {
+    xy_from_index(d.u16()? as usize)
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &RoomXY, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.u16(xy_to_linear_index(*v) as u16)?;
+    Ok(())
+  }
+}
+
+/// A list of room coordinates stored as runs of consecutive linear indices.
+///
+/// Each run is a start index followed by how many tiles it covers, so lines of
+/// ramparts or blocks of planned structures take a few bytes. The order of the
+/// list is kept.
+pub mod xy_runs {
+  use super::*;
+
+  /// Group the linear indices into `(start, length)` runs.
+  pub(super) fn to_runs(xys: &[RoomXY]) -> Vec<(u16, u16)> {
+    let mut runs: Vec<(u16, u16)> = Vec::new();
+    for xy in xys {
+      let index = xy_to_linear_index(*xy) as u16;
+      match runs.last_mut() {
+        Some((start, len)) if *start + *len == index => *len += 1,
+        _ => runs.push((index, 1)),
+      }
+    }
+    runs
+  }
+
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<Vec<RoomXY>, decode::Error> {
+    let size = d.array()?
+      .ok_or(decode::Error::message("xy runs did not have set length"))?;
+    if size % 2 != 0 {
+      return Err(decode::Error::message("xy runs were not start and length pairs"));
+    }
+    let mut xys = Vec::new();
+    for _ in 0..size / 2 {
+      let start = d.u16()? as usize;
+      let len = d.u16()? as usize;
+      for index in start..start + len {
+        xys.push(xy_from_index(index)?);
+      }
+    }
+    Ok(xys)
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &Vec<RoomXY>, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    let runs = to_runs(v);
+    e.array(runs.len() as u64 * 2)?;
+    for (start, len) in runs {
+      e.u16(start)?.u16(len)?;
+    }
+    Ok(())
+  }
+}
+
+/// Run-length encode a full room of tiles as `(count, value)` byte pairs.
+fn rle_encode(tiles: impl Iterator<Item = u8>) -> Vec<u8> {
+  let mut out: Vec<u8> = Vec::new();
+  for value in tiles {
+    match out.len() {
+      len if len >= 2 && out[len - 1] == value && out[len - 2] < u8::MAX =>
+        out[len - 2] += 1,
+      _ => out.extend([1, value]),
+    }
+  }
+  out
+}
+
+/// Decode `(count, value)` byte pairs, calling `set` for every tile index.
+fn rle_decode(bytes: &[u8], mut set: impl FnMut(usize, u8)) -> Result<(), decode::Error> {
+  if bytes.len() % 2 != 0 {
+    return Err(decode::Error::message("run length bytes were not count and value pairs"));
+  }
+  let mut index = 0;
+  for pair in bytes.chunks_exact(2) {
+    let (count, value) = (pair[0] as usize, pair[1]);
+    if index + count > ROOM_AREA {
+      return Err(decode::Error::message("run length encoding covers more than a room"));
+    }
+    for i in index..index + count {
+      set(i, value);
+    }
+    index += count;
+  }
+  if index != ROOM_AREA {
+    return Err(decode::Error::message("run length encoding does not cover the room"));
+  }
+  Ok(())
+}
+
+/// `LocalCostMatrix` stored with run-length encoding.
+pub mod cost_matrix {
+  use super::*;
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<LocalCostMatrix, decode::Error> {
+    let mut bits = [0; ROOM_AREA];
+    rle_decode(d.bytes()?, |i, value| bits[i] = value)?;
+    let mut matrix = LocalCostMatrix::new();
+    for ((_, cost), value) in matrix.iter_mut().zip(bits) {
+      *cost = value;
+    }
+    Ok(matrix)
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &LocalCostMatrix, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.bytes(&rle_encode(v.get_bits().iter().copied()))?;
+    Ok(())
+  }
+}
+
+/// `TileMap<u8>` stored with run-length encoding.
+pub mod tile_map {
+  use super::*;
+  pub fn decode<'b, Ctx>(
+    d: &mut Decoder<'b>, ctx: &mut Ctx
+  ) -> Result<TileMap<u8>, decode::Error> {
+    let mut map = TileMap::default();
+    rle_decode(d.bytes()?, |i, value| map[i] = value)?;
+    Ok(map)
+  }
+
+  pub fn encode<Ctx, W: Write>(
+    v: &TileMap<u8>, e: &mut Encoder<W>, ctx: &mut Ctx
+  ) -> Result<(), encode::Error<W::Error>> {
+    e.bytes(&rle_encode((0..ROOM_AREA).map(|i| v[i])))?;
+    Ok(())
+  }
+}
+
+/// Encoding for the keys and values of persisted caches, which can't use
+/// `#[cbor(with = "...")]` to pick a codec.
+pub trait CacheCodec: Sized {
+  fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>>;
+  fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error>;
+}
+
+macro_rules! codec_derived {
+  ($($t:ty)*) => {
+    $(
+      impl CacheCodec for $t {
+        fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+          e.encode(self)?;
+          Ok(())
+        }
+        fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+          d.decode()
+        }
+      }
+    )*
+  };
+}
+
+macro_rules! codec_with {
+  ($($t:ty => $m:ident)*) => {
+    $(
+      impl CacheCodec for $t {
+        fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+          $m::encode(self, e, &mut ())
+        }
+        fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+          $m::decode(d, &mut ())
+        }
+      }
+    )*
+  };
+}
+
+codec_derived!(bool u8 u16 u32 String);
+codec_with! {
+  RoomName => room_name
+  Position => position
+  RoomXY => room_xy
+  LocalCostMatrix => cost_matrix
+}
+
+impl<T> CacheCodec for ObjectId<T> {
+  fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+    object_id::encode(self, e, &mut ())
+  }
+  fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+    object_id::decode(d, &mut ())
+  }
+}
+
+impl<T: CacheCodec> CacheCodec for Option<T> {
+  fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+    match self {
+      Some(value) => value.encode_to(e),
+      None => {
+        e.null()?;
+        Ok(())
+      }
+    }
+  }
+  fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+    if d.datatype()? == minicbor::data::Type::Null {
+      d.null()?;
+      Ok(None)
+    } else {
+      T::decode_from(d).map(Some)
+    }
+  }
+}
+
+impl<T: CacheCodec> CacheCodec for Vec<T> {
+  fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+    e.array(self.len() as u64)?;
+    self.iter().try_for_each(|value| value.encode_to(e))
+  }
+  fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+    let len = d.array()?
+      .ok_or(decode::Error::message("cached vec did not have set length"))?;
+    (0..len).map(|_| T::decode_from(d)).collect()
+  }
+}
+
+impl<A: CacheCodec, B: CacheCodec> CacheCodec for (A, B) {
+  fn encode_to<W: Write>(&self, e: &mut Encoder<W>) -> Result<(), encode::Error<W::Error>> {
+    e.array(2)?;
+    self.0.encode_to(e)?;
+    self.1.encode_to(e)
+  }
+  fn decode_from(d: &mut Decoder<'_>) -> Result<Self, decode::Error> {
+    if d.array()? != Some(2) {
+      return Err(decode::Error::message("cached pair was not an array of two members"));
+    }
+    Ok((A::decode_from(d)?, B::decode_from(d)?))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, PartialEq, Encode, Decode)]
+  struct Holder {
+    #[n(0)] #[cbor(with = "room_name")] room: RoomName,
+    #[n(1)] #[cbor(with = "position")] pos: Position,
+    #[n(2)] #[cbor(with = "room_xy")] xy: RoomXY,
+    #[n(3)] #[cbor(with = "xy_runs")] xys: Vec<RoomXY>,
+  }
+
+  fn xy(x: u8, y: u8) -> RoomXY {
+    RoomXY::try_from((x, y)).unwrap()
+  }
+
+  #[test]
+  fn room_name_matches_position_packing() {
+    let pos = Position::new(RoomCoordinate::new(0).unwrap(), RoomCoordinate::new(0).unwrap(),
+                            RoomName::new("W12N3").unwrap());
+    let mut buffer = Vec::new();
+    room_name::encode(&pos.room_name(), &mut Encoder::new(&mut buffer), &mut ()).unwrap();
+    assert_eq!(Decoder::new(&buffer).u16().unwrap() as u32, pos.packed_repr() >> 16);
+  }
+
+  #[test]
+  fn positional_round_trip() {
+    let room = RoomName::new("W12N3").unwrap();
+    let holder = Holder {
+      room,
+      pos: Position::new(RoomCoordinate::new(7).unwrap(), RoomCoordinate::new(49).unwrap(),
+                         RoomName::new("E4S20").unwrap()),
+      xy: xy(49, 0),
+      xys: vec![xy(3, 4), xy(3, 5), xy(3, 6), xy(10, 10), xy(3, 7)],
+    };
+    let buffer = minicbor::to_vec(&holder).unwrap();
+    assert_eq!(minicbor::decode::<Holder>(&buffer).unwrap(), holder);
+    assert_eq!(xy_runs::to_runs(&holder.xys).len(), 3);
+  }
+
+  #[test]
+  fn run_length_round_trip() {
+    let mut tiles = [0u8; ROOM_AREA];
+    tiles[300..700].fill(255);
+    tiles[1000] = 5;
+    let bytes = rle_encode(tiles.iter().copied());
+    assert!(bytes.len() < 30);
+    let mut decoded = [1u8; ROOM_AREA];
+    rle_decode(&bytes, |i, value| decoded[i] = value).unwrap();
+    assert_eq!(decoded, tiles);
+    assert!(rle_decode(&bytes[2..], |_, _| ()).is_err());
+  }
+}
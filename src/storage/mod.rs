@@ -1,3 +1,4 @@
 pub mod cbor;
 pub mod cache;
 pub mod serialization;
+pub mod segments;
@@ -0,0 +1,481 @@
+//! Splits the encoded memory string across multiple RawMemory segments.
+//!
+//! A single segment can only hold `MEMORY_SEGMENT_SIZE_LIMIT` UTF-16 units, so
+//! we keep a small manifest in `MANIFEST_SEGMENT` that lists which segments hold
+//! the data, how long it is and a checksum over it. The layout grows or shrinks
+//! each time we write.
+//!
+//! Data segments come in two banks that we alternate between, so the previous
+//! good encoding is still sitting in the other bank as a backup. If the current
+//! encoding turns out to be corrupt we copy it into `QUARANTINE_SEGMENTS` for
+//! offline inspection and roll back to the backup. If the manifest itself is
+//! unreadable we don't know which bank is current, so the raw manifest and
+//! both banks are copied into the quarantine as they are, and each bank is
+//! tried in turn.
+//!
+//! `CACHE_SEGMENT` is separate from all of that and holds the snapshots of the
+//! persisted caches, which we can always afford to lose.
+
+use std::ops::Range;
+
+use minicbor::{Encode, Decode};
+use base64::{Engine as _, engine::general_purpose};
+use screeps::constants::extra::{MEMORY_SEGMENT_SIZE_LIMIT, MEMORY_SEGMENT_ACTIVE_LIMIT};
+use screeps::raw_memory;
+use screeps::js_collections::JsHashMap;
+
+use log::*;
+
+/// The segment that always holds the manifest.
+pub const MANIFEST_SEGMENT: u8 = 0;
+
+/// Most keys `RawMemory.segments` can hold in a tick. The engine throws if
+/// there are more, counting the segments that were loaded as well as the
+/// ones we set.
+const MAX_SEGMENT_KEYS: u8 = MEMORY_SEGMENT_ACTIVE_LIMIT as u8;
+
+/// Most segments a single encoding can span.
+///
+/// A save has the manifest, the cache segment and the bank it loaded from in
+/// `RawMemory.segments`, and then writes the other bank, so both banks and
+/// those two segments have to fit in `MAX_SEGMENT_KEYS`.
+const MAX_SEGMENTS: u8 = (MAX_SEGMENT_KEYS - 2) / 2;
+
+/// The two banks of segments we alternate between when writing memory.
+pub const DATA_BANKS: [Range<u8>; 2] = [
+  1..(1 + MAX_SEGMENTS),
+  (1 + MAX_SEGMENTS)..(1 + 2 * MAX_SEGMENTS),
+];
+
+/// Where corrupted encodings get copied to so they can be looked at later.
+///
+/// Big enough for the raw manifest and both banks, for when the manifest is
+/// what got corrupted.
+pub const QUARANTINE_SEGMENTS: Range<u8> = (1 + 2 * MAX_SEGMENTS)..(2 + 4 * MAX_SEGMENTS);
+
+/// Holds the snapshots of the caches made with `mk_cache! { persist ... }`.
+pub const CACHE_SEGMENT: u8 = 2 + 4 * MAX_SEGMENTS;
+
+/// Maximum size of a single segment in UTF-16 units.
+const SEGMENT_SIZE: usize = MEMORY_SEGMENT_SIZE_LIMIT as usize;
+
+#[derive(Debug)]
+pub enum SegmentError {
+  /// The manifest segment could not be decoded.
+  Manifest,
+  /// A segment in the manifest wasn't in one of the `DATA_BANKS`.
+  BadSegment(u8),
+  /// The reassembled string wasn't the length the manifest said it would be.
+  Length { expected: u32, found: u32 },
+  /// The reassembled string didn't match the checksum in the manifest.
+  Checksum { expected: u32, found: u32 },
+  /// The data needs more segments than fit in a bank.
+  TooLarge { segments_needed: usize },
+}
+
+/// Describes where the memory string lives and how to check it.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct Manifest {
+  /// The segments holding the data, in order.
+  #[n(0)] pub segments: Vec<u8>,
+  /// Length of the full string in UTF-16 units.
+  #[n(1)] pub length: u32,
+  /// `checksum` of the full string.
+  #[n(2)] pub checksum: u32,
+  /// The last good encoding, which lives in the other bank.
+  #[n(3)] pub backup: Option<Box<Manifest>>,
+  /// The most recent encoding that failed to load, if any.
+  #[n(4)] pub quarantine: Option<Box<Manifest>>,
+}
+
+/// FNV-1a hash of the string. Cheap, and good enough to catch truncated
+/// or mangled segments.
+pub fn checksum(data: &str) -> u32 {
+  const OFFSET_BASIS: u32 = 0x811c9dc5;
+  const PRIME: u32 = 0x01000193;
+  data.bytes().fold(OFFSET_BASIS, |hash, byte| {
+    (hash ^ byte as u32).wrapping_mul(PRIME)
+  })
+}
+
+#[inline]
+fn utf16_len(data: &str) -> usize {
+  data.chars().map(char::len_utf16).sum()
+}
+
+/// Split the string into chunks that each fit in a segment.
+fn split_chunks(data: &str, size: usize) -> Vec<&str> {
+  let mut chunks = Vec::new();
+  let mut start = 0;
+  let mut units = 0;
+  for (idx, c) in data.char_indices() {
+    let len = c.len_utf16();
+    if units + len > size {
+      chunks.push(&data[start..idx]);
+      start = idx;
+      units = 0;
+    }
+    units += len;
+  }
+  if start < data.len() {
+    chunks.push(&data[start..]);
+  }
+  chunks
+}
+
+/// Write `data` into `range`, returning a manifest describing it.
+fn write_chunks(
+  segments: &JsHashMap<u8, String>, range: Range<u8>, data: &str
+) -> Result<Manifest, SegmentError> {
+  let (manifest, chunks) = Manifest::layout(data, range)?;
+  for (&index, chunk) in manifest.segments.iter().zip(chunks) {
+    segments.set(index, chunk.to_string());
+  }
+  Ok(manifest)
+}
+
+impl Manifest {
+  /// Decode the manifest from the contents of `MANIFEST_SEGMENT`.
+  ///
+  /// An empty segment means nothing has been written yet.
+  pub fn from_segment(raw: &str) -> Result<Manifest, SegmentError> {
+    if raw.is_empty() {
+      return Ok(Manifest::default());
+    }
+    let bytes = general_purpose::STANDARD_NO_PAD.decode(raw)
+      .map_err(|_| SegmentError::Manifest)?;
+    minicbor::decode(&bytes).map_err(|_| SegmentError::Manifest)
+  }
+
+  pub fn to_segment(&self) -> String {
+    let bytes = minicbor::to_vec(self).expect("manifest encoding is infallible");
+    general_purpose::STANDARD_NO_PAD.encode(bytes)
+  }
+
+  /// The segments that need to be active to read the memory, plus the
+  /// `CACHE_SEGMENT` if there's room for it.
+  pub fn active_segments(&self) -> Vec<u8> {
+    let mut active = Vec::with_capacity(self.segments.len() + 2);
+    active.push(MANIFEST_SEGMENT);
+    active.extend(self.segments.iter().copied());
+    if active.len() < MEMORY_SEGMENT_ACTIVE_LIMIT as usize {
+      active.push(CACHE_SEGMENT);
+    }
+    active
+  }
+
+  /// Check the reassembled string against the manifest.
+  pub fn verify(&self, data: &str) -> Result<(), SegmentError> {
+    let length = utf16_len(data) as u32;
+    if length != self.length {
+      return Err(SegmentError::Length { expected: self.length, found: length });
+    }
+    let sum = checksum(data);
+    if sum != self.checksum {
+      return Err(SegmentError::Checksum { expected: self.checksum, found: sum });
+    }
+    Ok(())
+  }
+
+  /// Just the location of the data, without the backup or quarantine.
+  fn location(&self) -> Manifest {
+    Manifest {
+      segments: self.segments.clone(),
+      length: self.length,
+      checksum: self.checksum,
+      backup: None,
+      quarantine: None,
+    }
+  }
+
+  /// The bank that isn't holding the current data.
+  fn next_bank(&self) -> Range<u8> {
+    match self.segments.first() {
+      Some(index) if DATA_BANKS[0].contains(index) => DATA_BANKS[1].clone(),
+      _ => DATA_BANKS[0].clone(),
+    }
+  }
+
+  /// Build the manifest and chunks for writing `data` into `range`.
+  fn layout(data: &str, range: Range<u8>) -> Result<(Manifest, Vec<&str>), SegmentError> {
+    let chunks = split_chunks(data, SEGMENT_SIZE);
+    if chunks.len() > range.len() {
+      return Err(SegmentError::TooLarge { segments_needed: chunks.len() });
+    }
+    let manifest = Manifest {
+      segments: range.take(chunks.len()).collect(),
+      length: utf16_len(data) as u32,
+      checksum: checksum(data),
+      backup: None,
+      quarantine: None,
+    };
+    Ok((manifest, chunks))
+  }
+}
+
+/// Read the manifest from the loaded segments.
+///
+/// Returns `None` if the manifest segment isn't active yet.
+pub fn read_manifest(
+  segments: &JsHashMap<u8, String>
+) -> Option<Result<Manifest, SegmentError>> {
+  segments.get(MANIFEST_SEGMENT).map(|raw| Manifest::from_segment(&raw))
+}
+
+/// Reassemble the memory string from the segments listed in the manifest.
+///
+/// Returns `Ok(None)` if some of the segments haven't been loaded yet, in which
+/// case they have been requested and should be available next tick. The result
+/// still needs to be checked with `Manifest::verify`.
+pub fn read_segments(
+  segments: &JsHashMap<u8, String>, manifest: &Manifest
+) -> Result<Option<String>, SegmentError> {
+  let mut data = String::with_capacity(manifest.length as usize);
+  for &index in manifest.segments.iter() {
+    if !DATA_BANKS.iter().any(|bank| bank.contains(&index)) {
+      return Err(SegmentError::BadSegment(index));
+    }
+    match segments.get(index) {
+      Some(chunk) => data.push_str(&chunk),
+      None => {
+        debug!("memory segment {index} not loaded yet");
+        raw_memory::set_active_segments(&manifest.active_segments());
+        return Ok(None);
+      }
+    }
+  }
+  Ok(Some(data))
+}
+
+/// Write `data` into the bank the current data isn't in and update the manifest.
+///
+/// The current data becomes the backup, so this should only be called once it
+/// has loaded successfully. Segments the old backup used are cleared.
+pub fn write_segments(
+  segments: &JsHashMap<u8, String>, old: &Manifest, data: &str
+) -> Result<Manifest, SegmentError> {
+  let mut manifest = write_chunks(segments, old.next_bank(), data)?;
+  if let Some(backup) = old.backup.as_ref() {
+    for &index in backup.segments.iter() {
+      if !manifest.segments.contains(&index) {
+        segments.set(index, String::new());
+      }
+    }
+  }
+  if manifest.segments.len() != old.segments.len() {
+    info!("memory now spans {} segments", manifest.segments.len());
+  }
+  if !old.segments.is_empty() {
+    manifest.backup = Some(Box::new(old.location()));
+  }
+  manifest.quarantine = old.quarantine.clone();
+  segments.set(MANIFEST_SEGMENT, manifest.to_segment());
+  raw_memory::set_active_segments(&manifest.active_segments());
+  Ok(manifest)
+}
+
+/// Write the persisted cache snapshots to `CACHE_SEGMENT`.
+pub fn write_cache_segment(
+  segments: &JsHashMap<u8, String>, data: &str
+) -> Result<(), SegmentError> {
+  if utf16_len(data) > SEGMENT_SIZE {
+    return Err(SegmentError::TooLarge { segments_needed: utf16_len(data).div_ceil(SEGMENT_SIZE) });
+  }
+  segments.set(CACHE_SEGMENT, data.to_string());
+  Ok(())
+}
+
+/// Where the raw copy of `DATA_BANKS[bank]` goes in the quarantine, after the
+/// raw manifest.
+fn quarantine_slots(bank: usize) -> Range<u8> {
+  let start = QUARANTINE_SEGMENTS.start + 1 + bank as u8 * MAX_SEGMENTS;
+  start..(start + MAX_SEGMENTS)
+}
+
+/// Ask for `DATA_BANKS[bank]` to be loaded next tick, along with the manifest.
+pub fn request_bank(bank: usize) {
+  let mut active = vec![MANIFEST_SEGMENT];
+  active.extend(DATA_BANKS[bank].clone());
+  raw_memory::set_active_segments(&active);
+}
+
+/// Read `DATA_BANKS[bank]` without a manifest saying which of its segments
+/// are used, so take them up to the first empty one. Returns the location of
+/// the data along with it, or `None` if the bank has been requested and will
+/// be loaded next tick.
+pub fn read_bank(segments: &JsHashMap<u8, String>, bank: usize) -> Option<(Manifest, String)> {
+  let chunks: Option<Vec<String>> = DATA_BANKS[bank].clone()
+    .map(|index| segments.get(index))
+    .collect();
+  let Some(chunks) = chunks else {
+    request_bank(bank);
+    return None
+  };
+  let used = chunks.iter().take_while(|chunk| !chunk.is_empty()).count();
+  let data = chunks[..used].concat();
+  let location = Manifest {
+    segments: DATA_BANKS[bank].clone().take(used).collect(),
+    length: utf16_len(&data) as u32,
+    checksum: checksum(&data),
+    backup: None,
+    quarantine: None,
+  };
+  Some((location, data))
+}
+
+/// Copy the raw manifest and `DATA_BANKS[bank]` into the quarantine as they
+/// are. The bank has to be loaded.
+pub fn quarantine_bank(segments: &JsHashMap<u8, String>, bank: usize, raw_manifest: &str) {
+  segments.set(QUARANTINE_SEGMENTS.start, raw_manifest.to_string());
+  for (index, slot) in DATA_BANKS[bank].clone().zip(quarantine_slots(bank)) {
+    segments.set(slot, segments.get(index).unwrap_or_default());
+  }
+  error!("copied the unreadable manifest and memory bank {bank} into segments {:?}",
+         quarantine_slots(bank));
+}
+
+/// Write a new manifest once every bank has been looked at after the old
+/// one couldn't be read, pointing at `found` if a bank decoded. The
+/// quarantine covers all of `QUARANTINE_SEGMENTS`; its length and checksum
+/// are left at 0 since it holds raw copies rather than one encoding.
+pub fn recovered_manifest(segments: &JsHashMap<u8, String>, found: Option<Manifest>) -> Manifest {
+  let quarantine = Manifest {
+    segments: QUARANTINE_SEGMENTS.collect(),
+    ..Manifest::default()
+  };
+  let manifest = Manifest {
+    quarantine: Some(Box::new(quarantine)),
+    ..found.unwrap_or_default()
+  };
+  segments.set(MANIFEST_SEGMENT, manifest.to_segment());
+  raw_memory::set_active_segments(&manifest.active_segments());
+  manifest
+}
+
+pub enum RollBack {
+  /// Switched over to the backup, which will be readable next tick.
+  Restored(Manifest),
+  /// There was no backup, so we have to start over from nothing.
+  NoBackup(Manifest),
+}
+
+/// Handle the current data failing to load.
+///
+/// Copies `corrupt` into `QUARANTINE_SEGMENTS`, then switches the manifest over
+/// to the backup if there is one and requests its segments.
+pub fn roll_back(
+  segments: &JsHashMap<u8, String>, manifest: &Manifest, corrupt: &str
+) -> RollBack {
+  let quarantine = if corrupt.is_empty() {
+    manifest.quarantine.clone()
+  } else {
+    match write_chunks(segments, QUARANTINE_SEGMENTS, corrupt) {
+      Ok(quarantine) => {
+        error!("copied corrupted memory into segments {:?}", quarantine.segments);
+        Some(Box::new(quarantine))
+      }
+      Err(err) => {
+        error!("could not quarantine corrupted memory: {err:?}");
+        manifest.quarantine.clone()
+      }
+    }
+  };
+
+  match manifest.backup.as_ref() {
+    Some(backup) => {
+      let mut restored = backup.location();
+      restored.quarantine = quarantine;
+      error!("rolling memory back to the backup in segments {:?}", restored.segments);
+      segments.set(MANIFEST_SEGMENT, restored.to_segment());
+      raw_memory::set_active_segments(&restored.active_segments());
+      RollBack::Restored(restored)
+    }
+    None => {
+      error!("no memory backup to roll back to");
+      RollBack::NoBackup(Manifest { quarantine, ..Manifest::default() })
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn chunks_reassemble() {
+    let data = "abcdefghij".repeat(7);
+    let chunks = split_chunks(&data, 16);
+    assert_eq!(chunks.len(), 5);
+    assert!(chunks.iter().all(|c| utf16_len(c) <= 16));
+    assert_eq!(chunks.concat(), data);
+  }
+
+  #[test]
+  fn chunks_respect_utf16_units() {
+    // each of these is two UTF-16 units.
+    let data = "\u{1F600}".repeat(5);
+    let chunks = split_chunks(&data, 3);
+    assert_eq!(chunks.len(), 5);
+    assert_eq!(chunks.concat(), data);
+  }
+
+  #[test]
+  fn manifest_round_trip() {
+    let data = "x".repeat(SEGMENT_SIZE + 10);
+    let (mut manifest, chunks) = Manifest::layout(&data, DATA_BANKS[0].clone())
+      .expect("layout");
+    assert_eq!(manifest.segments, vec![1, 2]);
+    assert_eq!(chunks.len(), 2);
+    manifest.backup = Some(Box::new(manifest.location()));
+    let decoded = Manifest::from_segment(&manifest.to_segment()).expect("decode");
+    assert_eq!(decoded, manifest);
+    assert!(decoded.verify(&data).is_ok());
+    assert!(decoded.verify(&data[1..]).is_err());
+  }
+
+  #[test]
+  fn banks_alternate() {
+    let (first, _) = Manifest::layout("abc", DATA_BANKS[0].clone()).expect("layout");
+    assert_eq!(first.next_bank(), DATA_BANKS[1]);
+    let (second, _) = Manifest::layout("abc", first.next_bank()).expect("layout");
+    assert_eq!(second.next_bank(), DATA_BANKS[0]);
+    assert_eq!(Manifest::default().next_bank(), DATA_BANKS[0]);
+  }
+
+  #[test]
+  fn a_full_save_stays_within_the_key_limit() {
+    use std::collections::HashSet;
+    let data = "x".repeat(SEGMENT_SIZE * MAX_SEGMENTS as usize);
+    let (current, _) = Manifest::layout(&data, DATA_BANKS[1].clone()).expect("layout");
+    assert_eq!(current.segments.len(), MAX_SEGMENTS as usize);
+    // everything loaded, plus every segment in the bank we write to, which is
+    // also where the old backup gets cleared from.
+    let mut keys: HashSet<u8> = current.active_segments().into_iter().collect();
+    keys.extend(current.next_bank());
+    keys.insert(MANIFEST_SEGMENT);
+    keys.insert(CACHE_SEGMENT);
+    assert!(keys.len() <= MAX_SEGMENT_KEYS as usize, "{} keys", keys.len());
+    // rolling back loads the corrupt bank and writes it to the quarantine.
+    let mut keys: HashSet<u8> = current.active_segments().into_iter().collect();
+    keys.extend(QUARANTINE_SEGMENTS.take(MAX_SEGMENTS as usize));
+    keys.insert(CACHE_SEGMENT);
+    assert!(keys.len() <= MAX_SEGMENT_KEYS as usize, "{} keys", keys.len());
+    // recovering a lost manifest loads it and one bank a tick, and copies
+    // both into the quarantine.
+    for bank in 0..DATA_BANKS.len() {
+      let mut keys: HashSet<u8> = DATA_BANKS[bank].clone().collect();
+      keys.insert(MANIFEST_SEGMENT);
+      keys.insert(QUARANTINE_SEGMENTS.start);
+      keys.extend(quarantine_slots(bank));
+      assert!(keys.len() <= MAX_SEGMENT_KEYS as usize, "{} keys", keys.len());
+      assert!(quarantine_slots(bank).all(|slot| QUARANTINE_SEGMENTS.contains(&slot)));
+    }
+  }
+
+  #[test]
+  fn too_large_is_an_error() {
+    let data = "x".repeat(SEGMENT_SIZE * MAX_SEGMENTS as usize + 1);
+    assert!(matches!(Manifest::layout(&data, DATA_BANKS[0].clone()),
+                     Err(SegmentError::TooLarge { .. })));
+  }
+}
@@ -1,4 +1,4 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::ops::{Deref, DerefMut};
 use std::default::Default;
 use std::collections::hash_map::Entry;
@@ -11,19 +11,27 @@ use js_sys::{JsString, Object, Reflect};
 use base64::{Engine as _, engine::general_purpose};
 
 use screeps::raw_memory;
+use screeps::js_collections::JsHashMap;
 use screeps::local::ObjectId;
 use screeps::objects::{Creep, StructureSpawn};
 use screeps::prelude::*;
 
 use log::*;
 use crate::memory::Memory;
+use crate::memory::migration;
+use crate::storage::cache;
 use crate::storage::cbor;
+use crate::storage::segments::{self, SegmentError, Manifest, RollBack};
 
 #[derive(Debug)]
 enum MemError {
   Base64(base64::DecodeError),
   DecodeCbor(minicbor::decode::Error),
   EncodeCbor,
+  Segments(SegmentError),
+  /// The packed UTF-16 string was malformed.
+  Unpack(&'static str),
+  Decompress(lz4_flex::block::DecompressError),
 }
 
 impl From<base64::DecodeError> for MemError {
@@ -38,6 +46,18 @@ impl From<minicbor::decode::Error> for MemError {
   }
 }
 
+impl From<lz4_flex::block::DecompressError> for MemError {
+  fn from(err: lz4_flex::block::DecompressError) -> MemError {
+    MemError::Decompress(err)
+  }
+}
+
+impl From<SegmentError> for MemError {
+  fn from(err: SegmentError) -> MemError {
+    MemError::Segments(err)
+  }
+}
+
 impl<T: Debug> From<minicbor::encode::Error<T>> for MemError {
   fn from(err: minicbor::encode::Error<T>) -> MemError {
     warn!("Encoding error with cbor: {:?}", err);
@@ -46,35 +66,171 @@ impl<T: Debug> From<minicbor::encode::Error<T>> for MemError {
 }
 
 fn from_buffer(buffer: &[u8]) -> Result<Memory, MemError> {
-  Ok(minicbor::decode(buffer)?)
+  Ok(migration::decode_versioned(buffer)?)
 }
 
 fn to_buffer(memory: &Memory, buffer: &mut Vec<u8>) -> Result<(), MemError> {
   let mut encoder = Encoder::new(buffer);
-  encoder.encode(memory)?;
+  migration::encode_versioned(memory, &mut encoder)?;
   Ok(())
 }
 
-// For the memory we're going to keep around a vector in the local heap
-// that we'll clear and deserialize the memory into every time we need it.
-fn to_mem_string(data: &[u8]) -> String {
-  general_purpose::STANDARD_NO_PAD.encode(data)
+/// The formats the memory string can be in, picked by its first character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MemFormat {
+  /// `STANDARD_NO_PAD` base64 with no header. This is what we used to write.
+  Base64,
+  /// 15 bits per UTF-16 code unit. See `pack_utf16`.
+  Packed,
+  /// Compressed with LZ4 and then packed.
+  Compressed,
+}
+
+/// Neither of these are in the base64 alphabet, so they can't be confused with
+/// the start of an old base64 string.
+const PACKED_HEADER: char = '#';
+const COMPRESSED_HEADER: char = '%';
+
+/// The format we write memory in.
+const MEM_FORMAT: MemFormat = MemFormat::Compressed;
+
+/// Added to every 15 bit value so we stay clear of control characters and
+/// the surrogate range (0xD800 to 0xDFFF), which isn't safe in a JS string on
+/// its own. The largest value we produce is 0x8FFF.
+const PACK_OFFSET: u32 = 0x1000;
+
+#[inline]
+fn push_unit(out: &mut String, value: u32) {
+  out.push(char::from_u32(PACK_OFFSET + (value & 0x7FFF)).expect("below the surrogate range"));
+}
+
+/// Pack bytes into a string at 15 bits per UTF-16 code unit.
+///
+/// The first two units hold the number of bytes, since the last unit can be
+/// padded.
+fn pack_utf16(data: &[u8], out: &mut String) {
+  let len = data.len() as u32;
+  out.reserve(2 + (data.len() * 8 + 14) / 15);
+  push_unit(out, len >> 15);
+  push_unit(out, len);
+  let mut acc: u32 = 0;
+  let mut bits = 0;
+  for &byte in data {
+    acc = (acc << 8) | byte as u32;
+    bits += 8;
+    if bits >= 15 {
+      bits -= 15;
+      push_unit(out, acc >> bits);
+      acc &= (1 << bits) - 1;
+    }
+  }
+  if bits > 0 {
+    push_unit(out, acc << (15 - bits));
+  }
 }
 
-fn from_mem_string(string: String, target: &mut Vec<u8>) -> Result<(), MemError> {
-  // I may need to write a custom decoder to avoid a redudant copy?
-  // but that's premature right now.
+/// Inverse of `pack_utf16`.
+fn unpack_utf16(packed: &str, target: &mut Vec<u8>) -> Result<(), MemError> {
+  let mut units = packed.chars().map(|c| {
+    (c as u32).checked_sub(PACK_OFFSET)
+      .filter(|v| *v <= 0x7FFF)
+      .ok_or(MemError::Unpack("character outside of the packed range"))
+  });
+  let mut next = || units.next().unwrap_or(Err(MemError::Unpack("missing length")));
+  let len = ((next()? << 15) | next()?) as usize;
+  target.reserve(len);
+  let start = target.len();
+  let mut acc: u32 = 0;
+  let mut bits = 0;
+  for unit in units {
+    acc = (acc << 15) | unit?;
+    bits += 15;
+    while bits >= 8 && target.len() - start < len {
+      bits -= 8;
+      target.push((acc >> bits) as u8);
+      acc &= (1 << bits) - 1;
+    }
+  }
+  if target.len() - start != len {
+    return Err(MemError::Unpack("packed data was shorter than its length"));
+  }
+  Ok(())
+}
+
+fn to_mem_string(data: &[u8]) -> String {
+  let mut out = String::new();
+  match MEM_FORMAT {
+    MemFormat::Base64 => general_purpose::STANDARD_NO_PAD.encode_string(data, &mut out),
+    MemFormat::Packed => {
+      out.push(PACKED_HEADER);
+      pack_utf16(data, &mut out);
+    }
+    MemFormat::Compressed => {
+      out.push(COMPRESSED_HEADER);
+      pack_utf16(&lz4_flex::compress_prepend_size(data), &mut out);
+    }
+  }
+  out
+}
 
-  Ok(general_purpose::STANDARD_NO_PAD.decode_vec(string, target)?)
+fn from_mem_string(string: &str, target: &mut Vec<u8>) -> Result<(), MemError> {
+  let mut chars = string.chars();
+  let format = match chars.next() {
+    Some(PACKED_HEADER) => MemFormat::Packed,
+    Some(COMPRESSED_HEADER) => MemFormat::Compressed,
+    _ => MemFormat::Base64,
+  };
+  match format {
+    MemFormat::Base64 =>
+      general_purpose::STANDARD_NO_PAD.decode_vec(string, target)?,
+    MemFormat::Packed => unpack_utf16(chars.as_str(), target)?,
+    MemFormat::Compressed => {
+      let mut compressed = Vec::new();
+      unpack_utf16(chars.as_str(), &mut compressed)?;
+      target.extend(lz4_flex::decompress_size_prepended(&compressed)?);
+    }
+  }
+  Ok(())
 }
 
-fn load_mem(mem_str: String, buffer: &mut Vec<u8>) -> Result<Memory, MemError> {
+fn load_mem(mem_str: &str, buffer: &mut Vec<u8>) -> Result<Memory, MemError> {
   from_mem_string(mem_str, buffer)?;
   from_buffer(buffer.deref())
 }
 
+/// The decoded memory we keep around between ticks.
+struct HeapMemory {
+  memory: Memory,
+  /// Where the memory was last written to.
+  manifest: Manifest,
+  /// The tick the memory was last written to the segments.
+  last_saved: u32,
+}
+
+/// How far we've got recovering from a manifest that couldn't be read. See
+/// `recover_manifest`.
+struct ManifestRecovery {
+  /// The next bank to look through.
+  bank: usize,
+  /// Where the banks that decoded are, and the tick their memory is from.
+  found: Vec<(Manifest, u32)>,
+}
+
 thread_local! {
   static MEMORY_DECODE_BUFFER: RefCell<Vec<u8>> = RefCell::new(Vec::new());
+  static HEAP_MEMORY: RefCell<Option<HeapMemory>> = RefCell::new(None);
+  static MANIFEST_RECOVERY: RefCell<Option<ManifestRecovery>> = RefCell::new(None);
+  /// See `request_save`.
+  static SAVE_REQUESTED: Cell<bool> = Cell::new(false);
+}
+
+/// Ask for the memory to be written to the segments at the end of this tick
+/// instead of waiting for `Settings::save_interval`.
+///
+/// Use this for changes we couldn't recover after a global reset, like the
+/// memory for a newly spawned creep.
+pub fn request_save() {
+  SAVE_REQUESTED.set(true);
 }
 
 /// Quick utility thing for when I want to log a buffer as a hex string.
@@ -90,37 +246,226 @@ impl<'a> UpperHex for HexSlice<'a> {
   }
 }
 
-pub fn with_memory(fun: impl FnOnce(&mut Memory) -> ()) -> () {
-  const index: u8 = 1;
-  raw_memory::set_active_segments(&[index]);
+/// The manifest can't be read, so we don't know which bank holds the current
+/// memory. Look through one bank a tick, to stay within the segment key
+/// limit, copying each into the quarantine and noting it if it decodes. Once
+/// both have been looked at, point the manifest at the newest memory that
+/// decoded, or at nothing so we start fresh. The memory loads as usual the
+/// tick after.
+fn recover_manifest(active_segments: &JsHashMap<u8, String>, raw_manifest: &str) {
+  MANIFEST_RECOVERY.with(|recovery| {
+    let mut recovery = recovery.borrow_mut();
+    let state = recovery.get_or_insert_with(|| ManifestRecovery { bank: 0, found: Vec::new() });
+    let Some((location, data)) = segments::read_bank(active_segments, state.bank) else {
+      return
+    };
+    segments::quarantine_bank(active_segments, state.bank, raw_manifest);
+    let decoded = MEMORY_DECODE_BUFFER.with(|buf_refcell| {
+      let mut buffer = buf_refcell.borrow_mut();
+      buffer.clear();
+      load_mem(&data, buffer.deref_mut())
+    });
+    match decoded {
+      Ok(memory) => {
+        warn!("memory bank {} decoded, from tick {}", state.bank, memory.last_time);
+        state.found.push((location, memory.last_time));
+      }
+      Err(err) => error!("memory bank {} did not decode: {err:?}", state.bank),
+    }
+    state.bank += 1;
+    if state.bank < segments::DATA_BANKS.len() {
+      segments::request_bank(state.bank);
+      return
+    }
+    let found = recovery.take().map_or(Vec::new(), |state| state.found);
+    let newest = found.into_iter()
+      .max_by_key(|(_, last_time)| *last_time)
+      .map(|(location, _)| location);
+    match &newest {
+      Some(location) => error!("recovered memory from segments {:?}", location.segments),
+      None => error!("no memory bank decoded, starting over"),
+    }
+    segments::recovered_manifest(active_segments, newest);
+  })
+}
+
+/// Decode the memory from the segments, rolling back to the backup if needed.
+///
+/// Returns `None` if there's nothing we can run with this tick.
+fn load_from_segments() -> Option<HeapMemory> {
+  let active_segments = raw_memory::segments();
+  let manifest = match segments::read_manifest(&active_segments) {
+    None => {
+      raw_memory::set_active_segments(&[segments::MANIFEST_SEGMENT]);
+      warn!("manifest segment not loaded yet");
+      return None
+    }
+    Some(Err(err)) => {
+      error!("memory manifest is unreadable: {err:?}");
+      let raw_manifest = active_segments.get(segments::MANIFEST_SEGMENT).unwrap_or_default();
+      recover_manifest(&active_segments, &raw_manifest);
+      return None
+    }
+    Some(Ok(manifest)) => manifest,
+  };
+  let mem_str = match segments::read_segments(&active_segments, &manifest) {
+    Ok(None) => {
+      warn!("memory segments not loaded yet");
+      return None
+    }
+    Ok(Some(mem_str)) => mem_str,
+    Err(err) => {
+      error!("could not read memory segments: {err:?}");
+      String::new()
+    }
+  };
 
   MEMORY_DECODE_BUFFER.with(|buf_refcell| {
+    let mut buffer = buf_refcell.borrow_mut();
+    buffer.clear();
+    let loaded = manifest.verify(&mem_str)
+      .map_err(MemError::from)
+      .and_then(|()| load_mem(&mem_str, buffer.deref_mut()));
+    let (memory, manifest) = match loaded {
+      Ok(mem) => (mem, manifest),
+      Err(err) if manifest.segments.is_empty() => {
+        warn!("generating default memory because of error: {err:?}");
+        request_save();
+        (Memory::default(), manifest)
+      }
+      Err(err) => {
+        error!("memory failed to load: {err:?}");
+        match segments::roll_back(&active_segments, &manifest, &mem_str) {
+          // the backup gets loaded next tick.
+          RollBack::Restored(_) => return None,
+          RollBack::NoBackup(manifest) => {
+            error!("generating default memory");
+            request_save();
+            (Memory::default(), manifest)
+          }
+        }
+      }
+    };
+    load_persisted_caches(&active_segments, buffer.deref_mut());
+    Some(HeapMemory {
+      memory,
+      manifest,
+      last_saved: screeps::game::time(),
+    })
+  })
+}
+
+/// Restore the persisted caches from `CACHE_SEGMENT` after a global reset.
+fn load_persisted_caches(active_segments: &JsHashMap<u8, String>, buffer: &mut Vec<u8>) {
+  let Some(cache_str) = active_segments.get(segments::CACHE_SEGMENT) else {
+    // either memory needs every active segment or this is the first tick
+    // it was requested; in both cases we just recalculate.
+    warn!("cache segment is not active, persisted caches start empty");
+    cache::nothing_to_restore();
+    return
+  };
+  if cache_str.is_empty() {
+    cache::nothing_to_restore();
+    return
+  }
+  buffer.clear();
+  match from_mem_string(&cache_str, buffer) {
+    Ok(()) => cache::restore_persisted(buffer),
+    Err(err) => {
+      warn!("could not read the cache segment: {err:?}");
+      cache::nothing_to_restore();
+    }
+  }
+}
+
+/// Write the persisted caches to `CACHE_SEGMENT`.
+fn save_persisted_caches() {
+  let cache_str = to_mem_string(&cache::snapshot_persisted());
+  if let Err(err) = segments::write_cache_segment(&raw_memory::segments(), &cache_str) {
+    warn!("could not write the persisted caches: {err:?}");
+  }
+}
+
+/// Encode the memory and write it to the segments.
+fn save(heap: &mut HeapMemory) {
+  MEMORY_DECODE_BUFFER.with(|buf_refcell| {
+    let mut buffer = buf_refcell.borrow_mut();
+    buffer.clear();
+    to_buffer(&heap.memory, buffer.deref_mut()).expect("encoding problem");
+    let new_mem_str = to_mem_string(buffer.deref());
+    // TODO: at end clear the normal memory.
     let active_segments = raw_memory::segments();
-    //debug!("active segments {:?}", active_segments);
-    if let Some(mem_str) = active_segments.get(index) {
-      let mut buffer = buf_refcell.borrow_mut();
-      buffer.clear();
-      let mut memory = match load_mem(mem_str, buffer.deref_mut()) {
-        Err(err) => {
-          warn!("generating default memory because of error: {err:?}");
-          Memory::default()
-        },
-        Ok(mem) => mem
-      };
-      fun(&mut memory);
-      info!("memory state: {:?}", &memory);
-      memory.last_time = screeps::game::time();
-      buffer.clear();
-      to_buffer(&memory, buffer.deref_mut()).expect("encoding problem");
-      let new_mem_str = to_mem_string(buffer.deref());
-      // TODO: at end clear the normal memory. Also maybe switch to using raw memory segments?
-      active_segments.set(index, new_mem_str);
-    } else {
-      active_segments.set(index, "".to_string());
-      warn!("active segments not loaded yet");
+    match segments::write_segments(&active_segments, &heap.manifest, &new_mem_str) {
+      Ok(manifest) => {
+        heap.manifest = manifest;
+        heap.last_saved = heap.memory.last_time;
+      }
+      Err(err) => error!("could not write memory to segments: {err:?}"),
+    }
+  });
+}
+
+/// Look at the memory on the heap from outside of the game loop, e.g. from
+/// a console command.
+///
+/// Returns `None` if the memory hasn't been loaded yet.
+pub fn peek_memory<R>(fun: impl FnOnce(&Memory) -> R) -> Option<R> {
+  HEAP_MEMORY.with(|heap_refcell| {
+    heap_refcell.try_borrow().ok()?.as_ref().map(|heap| fun(&heap.memory))
+  })
+}
+
+/// Replace the memory on the heap from outside of the game loop and write
+/// it to the segments right away.
+///
+/// Returns `false` if the memory hasn't been loaded yet.
+pub fn replace_memory(memory: Memory) -> bool {
+  HEAP_MEMORY.with(|heap_refcell| {
+    let Ok(mut heap_opt) = heap_refcell.try_borrow_mut() else {
+      return false
+    };
+    let Some(heap) = heap_opt.as_mut() else {
+      return false
+    };
+    // keep `last_time` so the heap copy is still up to date next tick.
+    let last_time = heap.memory.last_time;
+    heap.memory = memory;
+    heap.memory.last_time = last_time;
+    save(heap);
+    true
+  })
+}
+
+/// Run `fun` with the memory.
+///
+/// The decoded memory stays on the heap between ticks. We only decode it from
+/// the segments after a global reset or if we missed a tick, and only write it
+/// back every `settings.save_interval` ticks or when `request_save` was called.
+pub fn with_memory(fun: impl FnOnce(&mut Memory) -> ()) -> () {
+  let time = screeps::game::time();
+  HEAP_MEMORY.with(|heap_refcell| {
+    let mut heap_opt = heap_refcell.borrow_mut();
+    let up_to_date = heap_opt.as_ref()
+      .map_or(false, |heap| heap.memory.last_time + 1 == time);
+    if !up_to_date {
+      if heap_opt.is_some() {
+        info!("heap memory is out of date, reloading from segments");
+      }
+      *heap_opt = load_from_segments();
+    }
+    let Some(heap) = heap_opt.as_mut() else {
+      return
+    };
+
+    fun(&mut heap.memory);
+    heap.memory.last_time = time;
+    if SAVE_REQUESTED.replace(false) || time >= heap.last_saved + heap.memory.settings.save_interval {
+      save(heap);
+    }
+    if time % cache::PERSIST_INTERVAL == 0 {
+      save_persisted_caches();
     }
   });
-  //raw_memory::set_active_segments(&[0]);
 }
 
 /*
@@ -191,3 +536,50 @@ mod tests {
   }
 }
 */
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample_bytes(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 37 % 251) as u8).collect()
+  }
+
+  #[test]
+  fn packing_round_trip() {
+    for len in [0, 1, 2, 14, 15, 16, 30, 31, 1000] {
+      let data = sample_bytes(len);
+      let mut packed = String::new();
+      pack_utf16(&data, &mut packed);
+      assert_eq!(packed.encode_utf16().count(), 2 + (len * 8 + 14) / 15);
+      let mut out = Vec::new();
+      unpack_utf16(&packed, &mut out).expect("unpack");
+      assert_eq!(out, data, "length {len}");
+    }
+  }
+
+  #[test]
+  fn mem_string_round_trip() {
+    let data = sample_bytes(500);
+    let mut out = Vec::new();
+    from_mem_string(&to_mem_string(&data), &mut out).expect("decode");
+    assert_eq!(out, data);
+  }
+
+  #[test]
+  fn loads_old_base64() {
+    let data = sample_bytes(100);
+    let old = general_purpose::STANDARD_NO_PAD.encode(&data);
+    let mut out = Vec::new();
+    from_mem_string(&old, &mut out).expect("decode");
+    assert_eq!(out, data);
+  }
+
+  #[test]
+  fn rejects_bad_packing() {
+    let mut out = Vec::new();
+    assert!(unpack_utf16("\u{1000}", &mut out).is_err());
+    assert!(unpack_utf16("\u{1000}\u{1005}\u{1000}", &mut out).is_err());
+    assert!(unpack_utf16("abc", &mut out).is_err());
+  }
+}
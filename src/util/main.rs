@@ -78,6 +78,13 @@ pub fn look_at_square(pos: Position, radius: i8) -> AreaInfo {
   build_area_info(results)
 }
 
+/// Our username, taken from one of our spawns.
+pub fn my_username() -> Option<String> {
+  game::spawns().values().next()
+    .and_then(|spawn| spawn.owner())
+    .map(|owner| owner.username())
+}
+
 pub fn find_closest_hostile(creep: &Creep) -> Option<Creep> {
   creep.pos().find_closest_by_path(find::HOSTILE_CREEPS, None)
 }
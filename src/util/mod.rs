@@ -1,4 +1,5 @@
 mod main;
 pub mod xy;
+pub mod travel;
 
 pub use main::*;
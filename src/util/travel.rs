@@ -0,0 +1,95 @@
+//! Moving creeps between rooms.
+//!
+//! `find_route` picks the rooms to pass through and the pathfinder is kept to
+//! those, so long trips don't wander. Each travelling role keeps its path in
+//! a `Travel` that lives on the heap only, and follows it a step at a time
+//! until the creep is knocked off of it.
+
+use std::collections::HashSet;
+
+use log::*;
+use serde::{Serialize, Deserialize};
+use screeps::game::map::FindRouteOptions;
+use screeps::pathfinder::{self, MultiRoomCostResult, SearchOptions};
+use screeps::{prelude::*, game, Creep, ErrorCode, Position, RoomCoordinate, RoomName};
+
+use super::PrettyId;
+
+/// Pathfinder operations allowed for each room on the route.
+const OPS_PER_ROOM: u32 = 2000;
+
+/// The path a creep is following and where it leads. This isn't persisted,
+/// so it's planned again after a global reset.
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Travel {
+  goal: Option<Position>,
+  path: Vec<Position>,
+}
+
+/// Where to head for in a room we may not have vision of.
+pub fn room_center(room: RoomName) -> Position {
+  let mid = RoomCoordinate::new(25).unwrap();
+  Position::new(mid, mid, room)
+}
+
+/// Path from `from` to within `range` of `goal`, through the rooms on the
+/// route between them.
+pub fn plan(from: Position, goal: Position, range: u32) -> Vec<Position> {
+  let mut rooms: HashSet<RoomName> = HashSet::from([from.room_name(), goal.room_name()]);
+  if from.room_name() != goal.room_name() {
+    match game::map::find_route(from.room_name(), goal.room_name(), Some(FindRouteOptions::new())) {
+      Ok(route) => rooms.extend(route.iter().map(|step| step.room)),
+      Err(err) => {
+        warn!("no route from {} to {}: {err:?}", from.room_name(), goal.room_name());
+        return Vec::new()
+      }
+    }
+  }
+  let num_rooms = rooms.len() as u32;
+  let opts = SearchOptions::new(move |room| if rooms.contains(&room) {
+      MultiRoomCostResult::Default
+    } else {
+      MultiRoomCostResult::Impassable
+    })
+    .plain_cost(2)
+    .swamp_cost(10)
+    .max_rooms(num_rooms.min(64) as u8)
+    .max_ops(OPS_PER_ROOM * num_rooms);
+  let result = pathfinder::search(from, goal, range, Some(opts));
+  if result.incomplete() {
+    debug!("incomplete path from {from} to {goal}");
+  }
+  result.path()
+}
+
+/// Move toward `goal` until within `range` of it, across rooms if needed.
+/// Returns whether the creep is there.
+pub fn travel_to(creep: &Creep, travel: &mut Travel, goal: Position, range: u32) -> bool {
+  let pos = creep.pos();
+  if pos.room_name() == goal.room_name() && pos.in_range_to(goal, range) {
+    *travel = Travel::default();
+    return true
+  }
+  if travel.goal != Some(goal) {
+    *travel = Travel { goal: Some(goal), path: Vec::new() };
+  }
+  if let Some(reached) = travel.path.iter().position(|step| *step == pos) {
+    travel.path.drain(..=reached);
+  }
+  match travel.path.first() {
+    Some(next) if next.get_range_to(pos) == 1 => (),
+    // knocked off of the path, or we haven't got one.
+    _ => travel.path = plan(pos, goal, range),
+  }
+  let Some(next) = travel.path.first().copied() else {
+    return false
+  };
+  let Some(direction) = pos.get_direction_to(next) else {
+    return false
+  };
+  match creep.move_direction(direction) {
+    Ok(()) | Err(ErrorCode::Tired) => (),
+    Err(err) => warn!("Creep {} couldn't travel because: {err:?}", creep.id_str()),
+  }
+  false
+}